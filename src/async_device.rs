@@ -0,0 +1,344 @@
+use std::future::Future;
+use std::time::{Duration};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::{
+    KsData, KsDecoder, KsValue, KsMessage, KsLimits, KsError, KsResult, ScpiError,
+    DEFAULT_TERMINATOR, DEFAULT_DRAIN_TIMEOUT,
+};
+use crate::device::{check_command, is_query, unexpected, MAX_ERROR_QUEUE};
+
+
+async fn with_timeout<T, F>(timeout: Option<Duration>, f: F) -> KsResult<T>
+where F: Future<Output = KsResult<T>> {
+    match timeout {
+        Some(to) => tokio::time::timeout(to, f).await
+        .unwrap_or(Err(KsError::Timeout { consumed: 0 })),
+        None => f.await,
+    }
+}
+
+/// Stream used by [`KsAsyncDevice`]
+///
+/// `read_end` has the same meaning as [`Transport::read_end`](crate::Transport::read_end),
+/// plain byte streams keep the default.
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Unpin {
+    fn read_end(&self) -> Option<bool> {
+        None
+    }
+}
+
+impl AsyncTransport for TcpStream {}
+
+impl AsyncTransport for DuplexStream {}
+
+impl<T: AsyncTransport + ?Sized> AsyncTransport for Box<T> {
+    fn read_end(&self) -> Option<bool> {
+        (**self).read_end()
+    }
+}
+
+/// Async counterpart of [`KsDevice`](crate::KsDevice) over tokio stream
+///
+/// Operations are cancellation-safe: when a future is dropped, e.g. on timeout,
+/// partially received response is kept and the next `receive` continues it,
+/// and partially sent message is completed before anything else is sent.
+/// Queries discard such pending response first, see [`response_pending`](Self::response_pending).
+pub struct KsAsyncDevice<S = TcpStream> {
+    inp: BufReader<S>,
+    decoder: KsDecoder,
+    terminator: Vec<u8>,
+    timeout: Option<Duration>,
+    error_check: bool,
+    /// Unsent rest of interrupted message
+    out: Vec<u8>,
+    /// Query was sent and its response is not completely received
+    pending: bool,
+}
+
+impl KsAsyncDevice<TcpStream> {
+    /// Connect to device, `timeout` is used for connecting and as default timeout of operations
+    pub async fn connect<A: ToSocketAddrs>(addr: A, timeout: Option<Duration>) -> KsResult<Self> {
+        let stream = with_timeout(timeout, async {
+            TcpStream::connect(addr).await.map_err(KsError::from)
+        }).await?;
+        stream.set_nodelay(true)?;
+        let mut device = Self::with_stream(stream);
+        device.set_timeout(timeout);
+        Ok(device)
+    }
+}
+
+impl<S: AsyncTransport> KsAsyncDevice<S> {
+    pub fn with_stream(stream: S) -> Self {
+        Self {
+            inp: BufReader::new(stream),
+            decoder: KsDecoder::new(KsLimits::default()),
+            terminator: Vec::from(DEFAULT_TERMINATOR),
+            timeout: None,
+            error_check: false,
+            out: Vec::new(),
+            pending: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.inp.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        self.inp.get_mut()
+    }
+
+    /// Take stream back, data buffered for reading is lost
+    pub fn into_inner(self) -> S {
+        self.inp.into_inner()
+    }
+
+    /// Timeout of each `send` and `receive`
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn set_limits(&mut self, limits: KsLimits) {
+        self.decoder.set_limits(limits);
+    }
+
+    pub fn limits(&self) -> &KsLimits {
+        self.decoder.limits()
+    }
+
+    /// Set bytes appended to each sent message
+    pub fn set_terminator(&mut self, terminator: &[u8]) {
+        self.terminator = Vec::from(terminator);
+    }
+
+    pub fn terminator(&self) -> &[u8] {
+        &self.terminator
+    }
+
+    /// Check error queue after each `write` and `query`, disabled by default
+    pub fn set_error_check(&mut self, enable: bool) {
+        self.error_check = enable;
+    }
+
+    pub fn error_check(&self) -> bool {
+        self.error_check
+    }
+
+    /// Send rest of interrupted message
+    async fn flush_pending(&mut self) -> KsResult<()> {
+        let stream = self.inp.get_mut();
+        while !self.out.is_empty() {
+            let num = stream.write(&self.out).await?;
+            if num == 0 {
+                return Err(KsError::Disconnected { consumed: 0 });
+            }
+            self.out.drain(..num);
+        }
+        stream.flush().await?;
+        Ok(())
+    }
+
+    async fn read_response(&mut self) -> KsResult<KsData> {
+        loop {
+            let buf = match self.inp.fill_buf().await {
+                Ok(buf) => buf,
+                Err(err) => {
+                    let consumed = self.decoder.consumed();
+                    self.decoder.reset();
+                    return Err(KsError::from(err).with_consumed(consumed));
+                },
+            };
+            if buf.is_empty() {
+                return self.decoder.finish();
+            }
+            let end = self.inp.get_ref().read_end();
+            // Buffer is filled already, so no I/O is done here
+            let (num, res) = self.decoder.decode_with_end(self.inp.buffer(), end);
+            self.inp.consume(num);
+            if let Some(res) = res {
+                return res;
+            }
+        }
+    }
+
+    /// Some query response is not completely received yet
+    ///
+    /// It is set after sending query and cleared by successful receive.
+    /// When it is set, `query` first clears the device, so that stale data
+    /// of the previous response, e.g. one arriving after timeout, is never returned.
+    pub fn response_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Discard any response data with [`DEFAULT_DRAIN_TIMEOUT`]
+    pub async fn clear(&mut self) -> KsResult<()> {
+        self.clear_timeout(DEFAULT_DRAIN_TIMEOUT).await
+    }
+
+    /// Complete interrupted message and discard input, including partially received response,
+    /// until no data arrives for `drain` time, stream has no out-of-band device clear
+    pub async fn clear_timeout(&mut self, drain: Duration) -> KsResult<()> {
+        with_timeout(self.timeout, self.flush_pending()).await?;
+        self.decoder.reset();
+        let num = self.inp.buffer().len();
+        self.inp.consume(num);
+        loop {
+            match tokio::time::timeout(drain, self.inp.fill_buf()).await {
+                Err(_) => break,
+                Ok(Ok([])) => return Err(KsError::Disconnected { consumed: 0 }),
+                Ok(Ok(buf)) => {
+                    let num = buf.len();
+                    self.inp.consume(num);
+                },
+                Ok(Err(err)) => return Err(err.into()),
+            }
+        }
+        self.pending = false;
+        Ok(())
+    }
+
+    /// Clear device if the previous response is pending
+    async fn resync(&mut self) -> KsResult<()> {
+        if self.pending {
+            self.clear().await
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn send(&mut self, data: &[u8]) -> KsResult<()> {
+        self.send_timeout(data, self.timeout).await
+    }
+
+    pub async fn receive(&mut self) -> KsResult<KsData> {
+        self.receive_timeout(self.timeout).await
+    }
+
+    pub async fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> KsResult<()> {
+        with_timeout(timeout, async {
+            self.flush_pending().await?;
+            self.out.extend_from_slice(data);
+            self.out.extend_from_slice(&self.terminator);
+            // Interrupted message is completed later, so its response is expected anyway
+            if is_query(data) {
+                self.pending = true;
+            }
+            self.flush_pending().await
+        }).await
+    }
+
+    /// Receive response, on timeout the response may be received later by the next call
+    pub async fn receive_timeout(&mut self, timeout: Option<Duration>) -> KsResult<KsData> {
+        let res = with_timeout(timeout, async {
+            self.flush_pending().await?;
+            self.read_response().await
+        }).await;
+        self.pending = match res {
+            // Response is consumed completely
+            Ok(_) | Err(KsError::NonUtf8Text) => false,
+            _ => true,
+        };
+        match res {
+            Err(KsError::Timeout { .. }) => Err(KsError::Timeout { consumed: self.decoder.consumed() }),
+            res => res,
+        }
+    }
+
+    async fn auto_check_errors(&mut self) -> KsResult<()> {
+        if self.error_check {
+            self.check_errors().await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send command adding terminator, command must not contain newlines
+    pub async fn write(&mut self, cmd: &str) -> KsResult<()> {
+        check_command(cmd)?;
+        self.send(cmd.as_bytes()).await?;
+        self.auto_check_errors().await
+    }
+
+    /// Send query and receive its response
+    pub async fn query(&mut self, cmd: &str) -> KsResult<KsData> {
+        check_command(cmd)?;
+        self.resync().await?;
+        self.send(cmd.as_bytes()).await?;
+        let data = self.receive().await?;
+        self.auto_check_errors().await?;
+        Ok(data)
+    }
+
+    /// Drain instrument error queue with `SYSTem:ERRor?`
+    pub async fn read_errors(&mut self) -> KsResult<Vec<ScpiError>> {
+        self.resync().await?;
+        let mut errors = Vec::new();
+        for _ in 0..MAX_ERROR_QUEUE {
+            self.send(b"SYST:ERR?").await?;
+            let entry = match self.receive().await? {
+                KsData::Text(text) => ScpiError::parse(&text)?,
+                KsData::Bin(_) => return Err(KsError::bad_response("binary error queue entry")),
+            };
+            if !entry.is_error() {
+                break;
+            }
+            errors.push(entry);
+        }
+        Ok(errors)
+    }
+
+    /// Drain error queue and fail if there were any errors
+    pub async fn check_errors(&mut self) -> KsResult<()> {
+        match KsError::from_errors(self.read_errors().await?) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Query single response data element
+    pub async fn query_value(&mut self, cmd: &str) -> KsResult<KsValue> {
+        self.query(cmd).await?.to_value()
+    }
+
+    /// Query compound response message
+    pub async fn query_message(&mut self, cmd: &str) -> KsResult<KsMessage> {
+        self.query(cmd).await?.to_message()
+    }
+
+    pub async fn query_f64(&mut self, cmd: &str) -> KsResult<f64> {
+        let v = self.query_value(cmd).await?;
+        v.as_f64().ok_or_else(|| unexpected("number", &v))
+    }
+
+    pub async fn query_i64(&mut self, cmd: &str) -> KsResult<i64> {
+        let v = self.query_value(cmd).await?;
+        v.as_i64().ok_or_else(|| unexpected("integer", &v))
+    }
+
+    pub async fn query_bool(&mut self, cmd: &str) -> KsResult<bool> {
+        let v = self.query_value(cmd).await?;
+        v.as_bool().ok_or_else(|| unexpected("boolean", &v))
+    }
+
+    /// Query string or character response data
+    pub async fn query_str(&mut self, cmd: &str) -> KsResult<String> {
+        let v = self.query_value(cmd).await?;
+        v.as_str_unquoted().map(String::from).ok_or_else(|| unexpected("string", &v))
+    }
+
+    /// Query binary block response
+    pub async fn query_block(&mut self, cmd: &str) -> KsResult<Vec<u8>> {
+        match self.query(cmd).await? {
+            KsData::Bin(data) => Ok(data),
+            KsData::Text(text) => Err(KsError::bad_response(format!("expected block, got {:?}", text))),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::convert::TryInto;
+use std::mem::size_of;
+
+use crate::{KsError, KsResult};
+
+
+/// Byte order of binary block elements as set by `FORMat:BORDer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ByteOrder {
+    /// `NORMal`, most significant byte first
+    #[default]
+    Normal,
+    /// `SWAPped`, least significant byte first
+    Swapped,
+}
+
+/// Numeric type binary block can be decoded into, matches `FORMat:DATA` types
+pub trait BlockElement: Sized + Copy {
+    /// Size of single element in bytes
+    const SIZE: usize;
+    /// Decode element from exactly `SIZE` bytes
+    fn from_bytes(bytes: &[u8], order: ByteOrder) -> Self;
+}
+
+macro_rules! impl_block_element {
+    ($($t:ty),*) => {$(
+        impl BlockElement for $t {
+            const SIZE: usize = size_of::<$t>();
+            fn from_bytes(bytes: &[u8], order: ByteOrder) -> Self {
+                let bytes = bytes.try_into().unwrap();
+                match order {
+                    ByteOrder::Normal => <$t>::from_be_bytes(bytes),
+                    ByteOrder::Swapped => <$t>::from_le_bytes(bytes),
+                }
+            }
+        }
+    )*};
+}
+
+impl_block_element!(i8, i16, i32, f32, f64);
+
+/// Decode binary block content as array of numbers
+pub fn decode_block<T: BlockElement>(data: &[u8], order: ByteOrder) -> KsResult<Vec<T>> {
+    if data.len() % T::SIZE != 0 {
+        return Err(KsError::bad_response(format!(
+            "block decode: length {} is not a multiple of element size {}",
+            data.len(), T::SIZE,
+        )));
+    }
+    Ok(data.chunks_exact(T::SIZE).map(|c| T::from_bytes(c, order)).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers() {
+        let data = [0x01, 0x02, 0xff, 0xfe];
+        assert_eq!(decode_block::<i8>(&data, ByteOrder::Normal).unwrap(), vec![1, 2, -1, -2]);
+        assert_eq!(decode_block::<i16>(&data, ByteOrder::Normal).unwrap(), vec![0x0102, -2]);
+        assert_eq!(decode_block::<i16>(&data, ByteOrder::Swapped).unwrap(), vec![0x0201, -257]);
+        assert_eq!(decode_block::<i32>(&data, ByteOrder::Normal).unwrap(), vec![0x0102fffe]);
+    }
+
+    #[test]
+    fn floats() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&1.5f64.to_be_bytes());
+        data.extend_from_slice(&(-2.0f64).to_be_bytes());
+        assert_eq!(decode_block::<f64>(&data, ByteOrder::Normal).unwrap(), vec![1.5, -2.0]);
+
+        let data = 0.25f32.to_le_bytes();
+        assert_eq!(decode_block::<f32>(&data, ByteOrder::Swapped).unwrap(), vec![0.25]);
+    }
+
+    #[test]
+    fn bad_length() {
+        match decode_block::<f32>(&[0; 6], ByteOrder::Normal) {
+            Err(KsError::BadResponse(_)) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use std::time::{Duration};
+
+
+/// Default interval of polling `*ESR?` by [`Completion::Poll`]
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Way of waiting for pending operations to complete
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Completion {
+    /// Blocking `*OPC?` query, instrument doesn't respond to anything else until it is done
+    #[default]
+    Query,
+    /// `*OPC` and then polling its bit with `*ESR?` every `interval`
+    ///
+    /// Reading `*ESR?` clears other event bits too.
+    Poll { interval: Duration },
+    /// `*OPC` with service request raised by event status bit,
+    /// transport has to deliver service requests, e.g. HiSLIP
+    ///
+    /// `*ESE` and `*SRE` are changed while waiting and restored after.
+    ServiceRequest,
+}
@@ -0,0 +1,308 @@
+use crate::{remove_newline, KsHook, KsData, KsLimits, KsLimitError, KsError, KsResult};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Nothing is read yet
+    Start,
+    Text,
+    /// `#` is read, waiting for number of size digits
+    Digits,
+    /// Reading size field of definite block
+    Size { digits: usize },
+    /// Reading block content, `None` size means indefinite block
+    Block { size: Option<usize> },
+    /// Reading line ending after definite block
+    End,
+}
+
+/// Incremental parser of single response independent of I/O
+///
+/// Input is fed chunk by chunk and parser state is kept between chunks,
+/// so blocking and async readers share the same framing logic
+/// and reading may be resumed after being interrupted between chunks.
+#[derive(Debug, Clone)]
+pub struct KsDecoder {
+    limits: KsLimits,
+    state: State,
+    /// Text line or block content
+    data: Vec<u8>,
+    /// Block size field or line ending after block
+    aux: Vec<u8>,
+    consumed: usize,
+    /// END indication of current input, see [`decode_with_end`](Self::decode_with_end)
+    end: Option<bool>,
+}
+
+impl KsDecoder {
+    pub fn new(limits: KsLimits) -> Self {
+        Self {
+            limits,
+            state: State::Start,
+            data: Vec::new(),
+            aux: Vec::new(),
+            consumed: 0,
+            end: None,
+        }
+    }
+
+    pub fn limits(&self) -> &KsLimits {
+        &self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: KsLimits) {
+        self.limits = limits;
+    }
+
+    /// Number of bytes of current response consumed so far
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+
+    /// Some part of response is already consumed
+    pub fn in_progress(&self) -> bool {
+        self.state != State::Start
+    }
+
+    /// Drop partially parsed response
+    pub fn reset(&mut self) {
+        self.state = State::Start;
+        self.data = Vec::new();
+        self.aux.clear();
+        self.consumed = 0;
+    }
+
+    /// Feed next chunk of input
+    ///
+    /// Returns number of bytes consumed from `input` and result if response is complete or broken.
+    /// After result is returned the decoder is ready for the next response.
+    /// Indefinite block ends at the first newline, use [`decode_with_end`](Self::decode_with_end)
+    /// if input comes from transport signaling END.
+    pub fn decode(&mut self, input: &[u8]) -> (usize, Option<KsResult<KsData>>) {
+        self.decode_with_end(input, None)
+    }
+
+    /// Feed next chunk of input with END indication of transport
+    ///
+    /// `Some(true)` means that `input` ends with END, `Some(false)` that it doesn't.
+    /// Then indefinite block ends only at END, so its content may contain newline bytes.
+    /// `None` means that END is unknown, same as [`decode`](Self::decode).
+    pub fn decode_with_end(&mut self, input: &[u8], end: Option<bool>) -> (usize, Option<KsResult<KsData>>) {
+        self.end = end;
+        let mut pos = 0;
+        let res = self.step_all(input, &mut pos);
+        self.consumed += pos;
+        let res = res.map(|res| res.map_err(|e| e.with_consumed(self.consumed)));
+        if res.is_some() {
+            self.reset();
+        }
+        (pos, res)
+    }
+
+    /// Finish response when input stream ended
+    pub fn finish(&mut self) -> KsResult<KsData> {
+        let res = match self.state {
+            // Line without newline at the end of stream is accepted as is
+            State::Text => self.take_text(),
+            State::End => {
+                if self.aux.is_empty() {
+                    Ok(KsData::from_bin(std::mem::take(&mut self.data)))
+                } else {
+                    Err(KsError::TrailingGarbage { consumed: self.consumed })
+                }
+            },
+            _ => Err(KsError::Disconnected { consumed: self.consumed }),
+        };
+        self.reset();
+        res
+    }
+
+    fn take_text(&mut self) -> KsResult<KsData> {
+        let mut text = std::mem::take(&mut self.data);
+        remove_newline(&mut text);
+        String::from_utf8(text)
+        .map(KsData::from_text)
+        .map_err(|_| KsError::NonUtf8Text)
+    }
+
+    fn step_all(&mut self, input: &[u8], pos: &mut usize) -> Option<KsResult<KsData>> {
+        while *pos < input.len() {
+            match self.step(&input[*pos..]) {
+                (num, None) => *pos += num,
+                (num, Some(res)) => {
+                    *pos += num;
+                    return Some(res);
+                },
+            }
+        }
+        None
+    }
+
+    /// Process non-empty input according to current state
+    fn step(&mut self, input: &[u8]) -> (usize, Option<KsResult<KsData>>) {
+        let exceeded = |size, limit| Some(Err(KsError::from(KsLimitError::BlockSize { size, limit })));
+        match self.state {
+            State::Start => {
+                if input[0] == b'#' {
+                    self.state = State::Digits;
+                    (1, None)
+                } else {
+                    self.state = State::Text;
+                    (0, None)
+                }
+            },
+            State::Text => {
+                let limit = self.limits.max_line_length;
+                let max = limit.map_or(input.len(), |limit| input.len().min(limit.saturating_sub(self.data.len())));
+                match input[..max].iter().position(|&b| b == b'\n') {
+                    Some(pos) => {
+                        self.data.extend_from_slice(&input[..=pos]);
+                        (pos + 1, Some(self.take_text()))
+                    },
+                    None => {
+                        self.data.extend_from_slice(&input[..max]);
+                        match limit {
+                            Some(limit) if self.data.len() >= limit => {
+                                (max, Some(Err(KsLimitError::LineLength { limit }.into())))
+                            },
+                            _ => (max, None),
+                        }
+                    },
+                }
+            },
+            State::Digits => match KsHook::block_digits(input[0]) {
+                Ok(0) => {
+                    self.state = State::Block { size: None };
+                    (1, None)
+                },
+                Ok(digits) => {
+                    self.state = State::Size { digits };
+                    (1, None)
+                },
+                Err(err) => (1, Some(Err(err))),
+            },
+            State::Size { digits } => {
+                let num = input.len().min(digits - self.aux.len());
+                self.aux.extend_from_slice(&input[..num]);
+                if self.aux.len() < digits {
+                    return (num, None);
+                }
+                let size = match String::from_utf8_lossy(&self.aux).parse::<usize>() {
+                    Ok(size) => size,
+                    Err(_) => return (num, Some(Err(KsError::BlockSizeParse))),
+                };
+                self.aux.clear();
+                if let Some(limit) = self.limits.max_block_size {
+                    if size > limit {
+                        return (num, exceeded(Some(size), limit));
+                    }
+                }
+                self.state = if size == 0 { State::End } else { State::Block { size: Some(size) } };
+                (num, None)
+            },
+            State::Block { size: Some(size) } => {
+                let num = input.len().min(size - self.data.len());
+                self.data.extend_from_slice(&input[..num]);
+                if self.data.len() == size {
+                    self.state = State::End;
+                }
+                (num, None)
+            },
+            State::Block { size: None } => {
+                let (len, last) = match self.end {
+                    // Newline before END is the terminator, others are content
+                    Some(true) if input.ends_with(b"\n") => (input.len() - 1, true),
+                    Some(end) => (input.len(), end),
+                    None => match input.iter().position(|&b| b == b'\n') {
+                        Some(pos) => (pos, true),
+                        None => (input.len(), false),
+                    },
+                };
+                if let Some(limit) = self.limits.max_block_size {
+                    if self.data.len() + len > limit {
+                        return (0, exceeded(None, limit));
+                    }
+                }
+                self.data.extend_from_slice(&input[..len]);
+                if last {
+                    let num = if input.len() > len { len + 1 } else { len };
+                    (num, Some(Ok(KsData::from_bin(std::mem::take(&mut self.data)))))
+                } else {
+                    (len, None)
+                }
+            },
+            State::End => match input.iter().position(|&b| b == b'\n') {
+                Some(pos) => {
+                    self.aux.extend_from_slice(&input[..=pos]);
+                    remove_newline(&mut self.aux);
+                    if self.aux.is_empty() {
+                        (pos + 1, Some(Ok(KsData::from_bin(std::mem::take(&mut self.data)))))
+                    } else {
+                        (pos + 1, Some(Err(KsError::TrailingGarbage { consumed: 0 })))
+                    }
+                },
+                None => {
+                    self.aux.extend_from_slice(input);
+                    (input.len(), None)
+                },
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunked_input() {
+        let input = b"#15abcde\r\ntext\n#0\x01\x02\n";
+        for chunk in 1..input.len() {
+            let mut decoder = KsDecoder::new(KsLimits::default());
+            let mut responses = Vec::new();
+            for part in input.chunks(chunk) {
+                let mut part = part;
+                while !part.is_empty() {
+                    let (num, res) = decoder.decode(part);
+                    part = &part[num..];
+                    if let Some(res) = res {
+                        responses.push(res.unwrap());
+                    }
+                }
+            }
+            assert_eq!(responses, vec![
+                KsData::from_bin(b"abcde".to_vec()),
+                KsData::from_text(String::from("text")),
+                KsData::from_bin(vec![1, 2]),
+            ]);
+            assert!(!decoder.in_progress());
+        }
+    }
+
+    #[test]
+    fn indefinite_block_end() {
+        let mut decoder = KsDecoder::new(KsLimits::default());
+        assert!(matches!(decoder.decode_with_end(b"#0\x01\n", Some(false)), (4, None)));
+        let (num, res) = decoder.decode_with_end(b"\x02\n", Some(true));
+        assert_eq!((num, res.unwrap().unwrap()), (2, KsData::from_bin(vec![1, b'\n', 2])));
+        assert!(!decoder.in_progress());
+
+        // Without END the first newline terminates block
+        let (num, res) = decoder.decode(b"#0\x01\n\x02\n");
+        assert_eq!((num, res.unwrap().unwrap()), (4, KsData::from_bin(vec![1])));
+    }
+
+    #[test]
+    fn finish() {
+        let mut decoder = KsDecoder::new(KsLimits::default());
+        assert!(matches!(decoder.decode(b"#12ab"), (5, None)));
+        assert_eq!(decoder.finish().unwrap(), KsData::from_bin(b"ab".to_vec()));
+        assert!(matches!(decoder.decode(b"abc"), (3, None)));
+        assert_eq!(decoder.finish().unwrap(), KsData::from_text(String::from("abc")));
+        match decoder.finish() {
+            Err(KsError::Disconnected { consumed: 0 }) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+}
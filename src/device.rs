@@ -0,0 +1,712 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::prelude::*;
+use std::io::{BufReader};
+use std::sync::mpsc::{Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    KsHook, KsData, KsValue, KsMessage, KsLimits, KsError, KsResult, ScpiError,
+    Transport, TcpTransport, TcpOptions, ReconnectPolicy, Completion,
+    StatusRegister, StatusByte, EventStatus, OperationStatus, QuestionableStatus, SrqDispatcher,
+    KsLockGuard, DEFAULT_POLL_INTERVAL,
+};
+
+
+/// Default program message terminator
+pub const DEFAULT_TERMINATOR: &[u8] = b"\r\n";
+
+/// Maximum number of entries read from error queue at once
+pub const MAX_ERROR_QUEUE: usize = 64;
+
+/// Default time of silence after which input is considered drained by `clear`
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Keysight LXI device we can connect and read/write data
+pub struct KsDevice<T: Transport = TcpTransport> {
+    inp: BufReader<T>,
+    limits: KsLimits,
+    terminator: Vec<u8>,
+    error_check: bool,
+    in_batch: bool,
+    reconnect: Option<ReconnectPolicy>,
+    init: Vec<String>,
+    /// Query was sent and its response is not completely received
+    pending: bool,
+    completion: Completion,
+    srq: SrqDispatcher,
+    /// Transport was asked to deliver service requests and its answer whether it can
+    srq_listening: Option<bool>,
+    /// Status byte had `MSS` bit at the last poll
+    mss: bool,
+}
+
+impl KsDevice<TcpTransport> {
+    pub fn new(addr: (String, u16), timeout: Option<Duration>) -> Self {
+        Self::with_limits(addr, timeout, KsLimits::default())
+    }
+
+    /// Create device with custom response size limits
+    pub fn with_limits(addr: (String, u16), timeout: Option<Duration>, limits: KsLimits) -> Self {
+        let mut device = Self::with_transport(TcpTransport::new(addr, TcpOptions::with_timeout(timeout)));
+        device.set_limits(limits);
+        device
+    }
+
+    pub fn address(&self) -> (&str, u16) {
+        self.transport().address()
+    }
+}
+
+impl<T: Transport> KsDevice<T> {
+    /// Create device communicating over given transport
+    pub fn with_transport(transport: T) -> Self {
+        Self {
+            inp: BufReader::new(transport),
+            limits: KsLimits::default(),
+            terminator: Vec::from(DEFAULT_TERMINATOR),
+            error_check: false,
+            in_batch: false,
+            reconnect: None,
+            init: Vec::new(),
+            pending: false,
+            completion: Completion::default(),
+            srq: SrqDispatcher::new(),
+            srq_listening: None,
+            mss: false,
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        self.inp.get_ref()
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        self.inp.get_mut()
+    }
+
+    /// Take transport back, data buffered for reading is lost
+    pub fn into_transport(self) -> T {
+        self.inp.into_inner()
+    }
+
+    /// Set both read and write timeout
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> KsResult<()> {
+        self.transport_mut().set_read_timeout(timeout)?;
+        self.transport_mut().set_write_timeout(timeout)
+    }
+
+    /// Read timeout
+    pub fn timeout(&self) -> Option<Duration> {
+        self.transport().read_timeout()
+    }
+
+    pub fn set_limits(&mut self, limits: KsLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &KsLimits {
+        &self.limits
+    }
+
+    /// Set bytes appended to each sent message
+    pub fn set_terminator(&mut self, terminator: &[u8]) {
+        self.terminator = Vec::from(terminator);
+    }
+
+    pub fn terminator(&self) -> &[u8] {
+        &self.terminator
+    }
+
+    /// Check error queue after each `write` and `query`, disabled by default
+    pub fn set_error_check(&mut self, enable: bool) {
+        self.error_check = enable;
+    }
+
+    pub fn error_check(&self) -> bool {
+        self.error_check
+    }
+
+    /// Restore lost connection automatically, disabled by default
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.reconnect = policy;
+    }
+
+    pub fn reconnect_policy(&self) -> Option<&ReconnectPolicy> {
+        self.reconnect.as_ref()
+    }
+
+    /// Set commands sent after each connect, e.g. data format settings
+    pub fn set_init_sequence<I, S>(&mut self, commands: I)
+    where I: IntoIterator<Item = S>, S: Into<String> {
+        self.init = commands.into_iter().map(Into::into).collect();
+    }
+
+    pub fn init_sequence(&self) -> &[String] {
+        &self.init
+    }
+
+    /// Set way of waiting in `wait_complete`, blocking `*OPC?` by default
+    pub fn set_completion(&mut self, completion: Completion) {
+        self.completion = completion;
+    }
+
+    pub fn completion(&self) -> Completion {
+        self.completion
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.transport().is_connected()
+    }
+
+    /// Connect and send init sequence
+    pub fn connect(&mut self) -> KsResult<()> {
+        self.discard_input();
+        self.pending = false;
+        self.transport_mut().connect()?;
+        let init = self.init.clone();
+        init.iter().try_for_each(|cmd| {
+            check_command(cmd)
+            .and_then(|()| self.send_raw(cmd.as_bytes()))
+        })
+    }
+
+    pub fn disconnect(&mut self) -> KsResult<()> {
+        self.discard_input();
+        self.pending = false;
+        self.transport_mut().disconnect()
+    }
+
+    pub fn reconnect(&mut self) -> KsResult<()> {
+        self.disconnect()
+        .and_then(|()| self.connect())
+    }
+
+    /// Drop data already read from transport but not yet parsed
+    fn discard_input(&mut self) {
+        let num = self.inp.buffer().len();
+        self.inp.consume(num);
+    }
+
+    /// Restore connection according to reconnect policy if `res` shows it was lost
+    fn recover<R>(&mut self, was_connected: bool, res: KsResult<R>) -> KsResult<R> {
+        match (res, self.reconnect.clone()) {
+            (Err(err @ KsError::Disconnected { .. }), Some(policy)) if was_connected => {
+                Err(self.restore(&policy, err))
+            },
+            (res, _) => res,
+        }
+    }
+
+    fn restore(&mut self, policy: &ReconnectPolicy, mut last: KsError) -> KsError {
+        for (i, delay) in policy.delays().enumerate() {
+            thread::sleep(delay);
+            let _ = self.disconnect();
+            match self.connect() {
+                Ok(()) => return KsError::Reconnected { attempts: i as u32 + 1 },
+                Err(err) => last = err,
+            }
+        }
+        last
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> KsResult<()> {
+        let mut msg = Vec::with_capacity(data.len() + self.terminator.len());
+        msg.extend_from_slice(data);
+        msg.extend_from_slice(&self.terminator);
+        self.transport_mut().send(&msg)
+    }
+
+    /// Some query response is not completely received yet
+    ///
+    /// It is set after sending query and cleared by successful receive.
+    /// When it is set, `query` first clears the device, so that stale data
+    /// of the previous response is never returned.
+    pub fn response_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Clear device and discard any response data with [`DEFAULT_DRAIN_TIMEOUT`]
+    pub fn clear(&mut self) -> KsResult<()> {
+        self.clear_timeout(DEFAULT_DRAIN_TIMEOUT)
+    }
+
+    /// Clear device using out-of-band device clear of transport if it has one,
+    /// and discard input until no data arrives for `drain` time
+    pub fn clear_timeout(&mut self, drain: Duration) -> KsResult<()> {
+        let synced = self.transport_mut().clear()?;
+        self.discard_input();
+        if !synced {
+            self.drain(drain)?;
+        }
+        self.pending = false;
+        Ok(())
+    }
+
+    fn drain(&mut self, timeout: Duration) -> KsResult<()> {
+        let dto = self.transport().read_timeout();
+        self.transport_mut().set_read_timeout(Some(timeout))?;
+        let mut buf = [0; 0x1000];
+        let res = loop {
+            match self.inp.read(&mut buf) {
+                Ok(0) => break Err(KsError::Disconnected { consumed: 0 }),
+                Ok(_) => (),
+                Err(err) => match KsError::from(err) {
+                    KsError::Timeout { .. } => break Ok(()),
+                    err => break Err(err),
+                },
+            }
+        };
+        self.transport_mut().set_read_timeout(dto)?;
+        res
+    }
+
+    /// Clear device if the previous response is pending
+    fn resync(&mut self) -> KsResult<()> {
+        if self.pending {
+            self.clear()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> KsResult<()> {
+        let was_connected = self.is_connected();
+        let res = self.send_raw(data);
+        if res.is_ok() && is_query(data) {
+            self.pending = true;
+        }
+        self.recover(was_connected, res)
+    }
+
+    pub fn receive(&mut self) -> KsResult<KsData> {
+        let was_connected = self.is_connected();
+        let res = KsHook::read_with_end(&mut self.inp, &self.limits, |inp| inp.get_ref().read_end());
+        self.pending = match res {
+            // Response is consumed completely
+            Ok(_) | Err(KsError::NonUtf8Text) => false,
+            _ => true,
+        };
+        self.recover(was_connected, res)
+    }
+
+    /// Receive binary block response streaming its content into `writer`,
+    /// see [`KsHook::read_block_into`] for details
+    pub fn receive_block_into<W, P>(&mut self, writer: &mut W, progress: P) -> KsResult<usize>
+    where W: Write, P: FnMut(usize, Option<usize>) {
+        let was_connected = self.is_connected();
+        let res = KsHook::read_block_with_end(&mut self.inp, writer, progress, |inp| inp.get_ref().read_end());
+        self.pending = res.is_err();
+        self.recover(was_connected, res)
+    }
+
+    pub fn send_timeout(&mut self, data: &[u8], timeout: Option<Duration>) -> KsResult<()> {
+        let dto = self.transport().write_timeout();
+        self.transport_mut().set_write_timeout(timeout)?;
+        let res = self.send(data);
+        self.transport_mut().set_write_timeout(dto)?;
+        res
+    }
+
+    pub fn receive_timeout(&mut self, timeout: Option<Duration>) -> KsResult<KsData> {
+        let dto = self.transport().read_timeout();
+        self.transport_mut().set_read_timeout(timeout)?;
+        let res = self.receive();
+        self.transport_mut().set_read_timeout(dto)?;
+        res
+    }
+
+    fn write_unchecked(&mut self, cmd: &str) -> KsResult<()> {
+        check_command(cmd)
+        .and_then(|()| self.send(cmd.as_bytes()))
+    }
+
+    fn auto_check_errors(&mut self) -> KsResult<()> {
+        if self.error_check && !self.in_batch {
+            self.check_errors()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send command adding terminator, command must not contain newlines
+    pub fn write(&mut self, cmd: &str) -> KsResult<()> {
+        self.write_unchecked(cmd)
+        .and_then(|()| self.auto_check_errors())
+    }
+
+    /// Send query and receive its response
+    pub fn query(&mut self, cmd: &str) -> KsResult<KsData> {
+        self.resync()
+        .and_then(|()| self.write_unchecked(cmd))
+        .and_then(|()| self.receive())
+        .and_then(|data| self.auto_check_errors().map(|()| data))
+    }
+
+    /// Drain instrument error queue with `SYSTem:ERRor?`
+    pub fn read_errors(&mut self) -> KsResult<Vec<ScpiError>> {
+        self.resync()?;
+        let mut errors = Vec::new();
+        for _ in 0..MAX_ERROR_QUEUE {
+            let entry = self.write_unchecked("SYST:ERR?")
+            .and_then(|()| self.receive())
+            .and_then(|data| match data {
+                KsData::Text(text) => ScpiError::parse(&text),
+                KsData::Bin(_) => Err(KsError::bad_response("binary error queue entry")),
+            })?;
+            if !entry.is_error() {
+                break;
+            }
+            errors.push(entry);
+        }
+        Ok(errors)
+    }
+
+    /// Drain error queue and fail if there were any errors
+    pub fn check_errors(&mut self) -> KsResult<()> {
+        match KsError::from_errors(self.read_errors()?) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Run several commands checking error queue only once at the end
+    pub fn batch<R, F>(&mut self, f: F) -> KsResult<R>
+    where F: FnOnce(&mut Self) -> KsResult<R> {
+        let in_batch = self.in_batch;
+        self.in_batch = true;
+        let res = f(self);
+        self.in_batch = in_batch;
+        res.and_then(|r| self.auto_check_errors().map(|()| r))
+    }
+
+    /// Query integer bypassing error queue check
+    fn query_register(&mut self, cmd: &str) -> KsResult<i64> {
+        self.resync()
+        .and_then(|()| self.write_unchecked(cmd))
+        .and_then(|()| self.receive())
+        .and_then(|data| data.to_value())
+        .and_then(|v| v.as_i64().ok_or_else(|| unexpected("integer", &v)))
+    }
+
+    /// Wait until all pending operations of instrument are complete
+    ///
+    /// Fails with `Timeout` if they are not complete within `timeout`,
+    /// which is independent of the device timeout.
+    pub fn wait_complete(&mut self, timeout: Duration) -> KsResult<()> {
+        match self.completion {
+            Completion::Query => self.complete_query(timeout),
+            Completion::Poll { interval } => self.complete_poll(timeout, interval),
+            Completion::ServiceRequest => self.complete_service_request(timeout),
+        }
+        .and_then(|()| self.auto_check_errors())
+    }
+
+    fn complete_query(&mut self, timeout: Duration) -> KsResult<()> {
+        self.resync()
+        .and_then(|()| self.write_unchecked("*OPC?"))
+        .and_then(|()| self.receive_timeout(Some(timeout)))
+        .and_then(|data| data.to_value())
+        .and_then(|v| match v.as_i64() {
+            Some(1) => Ok(()),
+            _ => Err(unexpected("1", &v)),
+        })
+    }
+
+    fn complete_poll(&mut self, timeout: Duration, interval: Duration) -> KsResult<()> {
+        let deadline = Instant::now() + timeout;
+        self.resync()
+        .and_then(|()| self.write_unchecked("*OPC"))?;
+        loop {
+            if self.query_register("*ESR?")? & i64::from(EventStatus::OPC.bits()) != 0 {
+                break Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break Err(KsError::Timeout { consumed: 0 });
+            }
+            thread::sleep(interval.min(deadline - now));
+        }
+    }
+
+    fn complete_service_request(&mut self, timeout: Duration) -> KsResult<()> {
+        let deadline = Instant::now() + timeout;
+        let ese = self.query_register("*ESE?")?;
+        let sre = self.query_register("*SRE?")?;
+        // Clear events occurred before
+        self.query_register("*ESR?")?;
+        let res = self.write_unchecked(&format!("*ESE {}", EventStatus::OPC.bits()))
+        .and_then(|()| self.write_unchecked(&format!("*SRE {}", StatusByte::ESB.bits())))
+        .and_then(|()| self.write_unchecked("*OPC"))
+        .and_then(|()| loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break Err(KsError::Timeout { consumed: 0 });
+            }
+            match self.transport_mut().wait_service_request(Some(deadline - now)) {
+                // Request may be left from another event
+                Ok(Some(_)) => match self.query_register("*ESR?") {
+                    Ok(esr) if esr & i64::from(EventStatus::OPC.bits()) == 0 => (),
+                    res => break res.map(|_| ()),
+                },
+                Ok(None) => break Err(KsError::Timeout { consumed: 0 }),
+                Err(err) => break Err(err),
+            }
+        });
+        let restore = self.write_unchecked(&format!("*ESE {}", ese))
+        .and_then(|()| self.write_unchecked(&format!("*SRE {}", sre)));
+        res.and(restore)
+    }
+
+    fn query_status<R>(&mut self, cmd: &str) -> KsResult<R>
+    where R: StatusRegister, u32: From<R::Bits>, R::Bits: TryFrom<i64> {
+        let value = self.query_i64(cmd)?;
+        R::Bits::try_from(value)
+        .map(R::from_bits_retain)
+        .map_err(|_| KsError::bad_response(format!("register value {} is out of range", value)))
+    }
+
+    fn write_status<R>(&mut self, cmd: &str, value: R) -> KsResult<()>
+    where R: StatusRegister, u32: From<R::Bits>, R::Bits: fmt::Display {
+        self.write(&format!("{} {}", cmd, value.bits()))
+    }
+
+    /// Read status byte with `*STB?`
+    pub fn status_byte(&mut self) -> KsResult<StatusByte> {
+        self.query_status("*STB?")
+    }
+
+    /// Read and clear standard event status register with `*ESR?`
+    pub fn event_status(&mut self) -> KsResult<EventStatus> {
+        self.query_status("*ESR?")
+    }
+
+    pub fn set_event_status_enable(&mut self, mask: EventStatus) -> KsResult<()> {
+        self.write_status("*ESE", mask)
+    }
+
+    pub fn event_status_enable(&mut self) -> KsResult<EventStatus> {
+        self.query_status("*ESE?")
+    }
+
+    /// Set status byte bits that raise service request, `MSS` bit is ignored
+    pub fn set_service_request_enable(&mut self, mask: StatusByte) -> KsResult<()> {
+        self.write_status("*SRE", mask)
+    }
+
+    pub fn service_request_enable(&mut self) -> KsResult<StatusByte> {
+        self.query_status("*SRE?")
+    }
+
+    /// Current state of operation status with `STAT:OPER:COND?`
+    pub fn operation_condition(&mut self) -> KsResult<OperationStatus> {
+        self.query_status("STAT:OPER:COND?")
+    }
+
+    /// Read and clear operation event register with `STAT:OPER?`
+    pub fn operation_event(&mut self) -> KsResult<OperationStatus> {
+        self.query_status("STAT:OPER?")
+    }
+
+    pub fn set_operation_enable(&mut self, mask: OperationStatus) -> KsResult<()> {
+        self.write_status("STAT:OPER:ENAB", mask)
+    }
+
+    pub fn operation_enable(&mut self) -> KsResult<OperationStatus> {
+        self.query_status("STAT:OPER:ENAB?")
+    }
+
+    /// Current state of questionable status with `STAT:QUES:COND?`
+    pub fn questionable_condition(&mut self) -> KsResult<QuestionableStatus> {
+        self.query_status("STAT:QUES:COND?")
+    }
+
+    /// Read and clear questionable event register with `STAT:QUES?`
+    pub fn questionable_event(&mut self) -> KsResult<QuestionableStatus> {
+        self.query_status("STAT:QUES?")
+    }
+
+    pub fn set_questionable_enable(&mut self, mask: QuestionableStatus) -> KsResult<()> {
+        self.write_status("STAT:QUES:ENAB", mask)
+    }
+
+    pub fn questionable_enable(&mut self) -> KsResult<QuestionableStatus> {
+        self.query_status("STAT:QUES:ENAB?")
+    }
+
+    fn listen_service_requests(&mut self) -> KsResult<()> {
+        if self.srq_listening.is_none() {
+            let dispatcher = self.srq.clone();
+            self.srq_listening = Some(self.transport_mut().listen_service_requests(dispatcher)?);
+        }
+        Ok(())
+    }
+
+    /// Receive status byte of each service request through channel
+    ///
+    /// Transports with service request channel, e.g. HiSLIP or VXI-11, deliver them as they arrive,
+    /// raw socket transport polls status byte over separate connection for them.
+    pub fn subscribe_service_requests(&mut self) -> KsResult<Receiver<StatusByte>> {
+        self.listen_service_requests()
+        .map(|()| self.srq.subscribe())
+    }
+
+    /// Call `callback` on each service request, see `subscribe_service_requests` for details
+    ///
+    /// Callback runs in transport listener thread, or in the thread calling `poll_service_request`
+    /// if transport can't deliver service requests.
+    pub fn on_service_request<F>(&mut self, callback: F) -> KsResult<()>
+    where F: FnMut(StatusByte) + Send + 'static {
+        self.listen_service_requests()
+        .map(|()| self.srq.add_callback(callback))
+    }
+
+    /// Read status byte and return it if instrument started requesting service since the previous poll
+    ///
+    /// Subscribers are notified too, unless transport delivers service requests by itself.
+    pub fn poll_service_request(&mut self) -> KsResult<Option<StatusByte>> {
+        let stb = self.status_byte()?;
+        let raised = stb.contains(StatusByte::MSS) && !self.mss;
+        self.mss = stb.contains(StatusByte::MSS);
+        if !raised {
+            return Ok(None);
+        }
+        if self.srq_listening != Some(true) {
+            self.srq.dispatch(stb);
+        }
+        Ok(Some(stb))
+    }
+
+    /// Acquire exclusive lock waiting for it up to `timeout`, fails with `Timeout` if it is not granted
+    ///
+    /// Transport locking is used if it has one, e.g. HiSLIP or VXI-11,
+    /// otherwise `SYST:LOCK:REQ?` of Keysight instruments is polled.
+    pub fn lock(&mut self, timeout: Duration) -> KsResult<KsLockGuard<'_, T>> {
+        self.acquire_lock(timeout, None)
+        .map(move |()| KsLockGuard::new(self))
+    }
+
+    /// Acquire shared lock with given name, only HiSLIP supports it
+    pub fn lock_shared(&mut self, name: &str, timeout: Duration) -> KsResult<KsLockGuard<'_, T>> {
+        self.acquire_lock(timeout, Some(name))
+        .map(move |()| KsLockGuard::new(self))
+    }
+
+    fn acquire_lock(&mut self, timeout: Duration, shared: Option<&str>) -> KsResult<()> {
+        let granted = match self.transport_mut().lock(timeout, shared) {
+            Err(KsError::Unsupported(_)) if shared.is_none() => self.poll_lock(timeout),
+            res => res,
+        }?;
+        if granted {
+            Ok(())
+        } else {
+            Err(KsError::Timeout { consumed: 0 })
+        }
+    }
+
+    fn poll_lock(&mut self, timeout: Duration) -> KsResult<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.query_bool("SYST:LOCK:REQ?")? {
+                break Ok(true);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break Ok(false);
+            }
+            thread::sleep(DEFAULT_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// Release lock held by this client, guard does it on drop
+    pub fn unlock(&mut self) -> KsResult<()> {
+        match self.transport_mut().unlock() {
+            Err(KsError::Unsupported(_)) => self.write("SYST:LOCK:REL"),
+            res => res,
+        }
+    }
+
+    /// Clear all event registers and error queue with `*CLS`
+    pub fn clear_status(&mut self) -> KsResult<()> {
+        self.write("*CLS")
+    }
+
+    /// Reset SCPI enable registers to their defaults with `STAT:PRES`
+    pub fn preset_status(&mut self) -> KsResult<()> {
+        self.write("STAT:PRES")
+    }
+
+    /// Query single response data element
+    pub fn query_value(&mut self, cmd: &str) -> KsResult<KsValue> {
+        self.query(cmd)
+        .and_then(|data| data.to_value())
+    }
+
+    /// Query compound response message
+    pub fn query_message(&mut self, cmd: &str) -> KsResult<KsMessage> {
+        self.query(cmd)
+        .and_then(|data| data.to_message())
+    }
+
+    pub fn query_f64(&mut self, cmd: &str) -> KsResult<f64> {
+        self.query_value(cmd)
+        .and_then(|v| v.as_f64().ok_or_else(|| unexpected("number", &v)))
+    }
+
+    pub fn query_i64(&mut self, cmd: &str) -> KsResult<i64> {
+        self.query_value(cmd)
+        .and_then(|v| v.as_i64().ok_or_else(|| unexpected("integer", &v)))
+    }
+
+    pub fn query_bool(&mut self, cmd: &str) -> KsResult<bool> {
+        self.query_value(cmd)
+        .and_then(|v| v.as_bool().ok_or_else(|| unexpected("boolean", &v)))
+    }
+
+    /// Query string or character response data
+    pub fn query_str(&mut self, cmd: &str) -> KsResult<String> {
+        self.query_value(cmd)
+        .and_then(|v| v.as_str_unquoted().map(String::from).ok_or_else(|| unexpected("string", &v)))
+    }
+
+    /// Query binary block response
+    pub fn query_block(&mut self, cmd: &str) -> KsResult<Vec<u8>> {
+        self.query(cmd)
+        .and_then(|data| match data {
+            KsData::Bin(data) => Ok(data),
+            KsData::Text(text) => Err(KsError::bad_response(format!("expected block, got {:?}", text))),
+        })
+    }
+}
+
+/// Message contains query header, i.e. `?` outside of quoted strings
+pub(crate) fn is_query(data: &[u8]) -> bool {
+    let mut quote = None;
+    data.iter().any(|&b| match quote {
+        Some(q) => {
+            if b == q {
+                quote = None;
+            }
+            false
+        },
+        None => match b {
+            b'"' | b'\'' => { quote = Some(b); false },
+            b'?' => true,
+            _ => false,
+        },
+    })
+}
+
+/// Check that command can be sent as single program message
+pub(crate) fn check_command(cmd: &str) -> KsResult<()> {
+    if cmd.contains('\n') {
+        Err(KsError::BadCommand(format!("newline inside command {:?}", cmd)))
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn unexpected(what: &str, value: &KsValue) -> KsError {
+    KsError::bad_response(format!("expected {}, got {:?}", what, value))
+}
@@ -0,0 +1,190 @@
+use std::io::{prelude::*, self};
+use std::thread;
+use std::time::{Duration};
+
+
+/// Misbehavior of emulated instrument applied to every response
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Wait before sending response
+    Delay(Duration),
+    /// Send first `after` bytes of response and wait before sending the rest
+    Stall { after: usize, duration: Duration },
+    /// Replace number of size digits after `#` of block response with non-digit
+    BadBlockHeader,
+    /// Replace size field of definite block response with non-digits
+    BadBlockSize,
+    /// Strip line ending from response
+    NoTerminator,
+    /// Close connection when given number of bytes is sent over it
+    DropAfter(usize),
+    /// Change each byte to random other value with given probability,
+    /// the same `seed` gives the same corruption on every connection
+    Corrupt { seed: u64, rate: f64 },
+}
+
+/// SplitMix64 generator, good enough for reproducible corruption
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0.0..1.0`
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn corrupt(rng: &mut Rng, data: &mut [u8], rate: f64) {
+    for b in data.iter_mut() {
+        if rng.next_f64() < rate {
+            // Xor with non-zero value so the byte is always changed
+            *b ^= (rng.next_u64() % 255 + 1) as u8;
+        }
+    }
+}
+
+/// Faults of single connection with its own byte counter and random state
+pub(crate) struct Injector {
+    faults: Vec<Fault>,
+    rng: Option<Rng>,
+    sent: usize,
+}
+
+impl Injector {
+    pub(crate) fn new(faults: &[Fault]) -> Self {
+        let rng = faults.iter().find_map(|fault| match *fault {
+            Fault::Corrupt { seed, .. } => Some(Rng(seed)),
+            _ => None,
+        });
+        Self { faults: faults.to_vec(), rng, sent: 0 }
+    }
+
+    /// Send part of response, connection is closed by error when byte limit is reached
+    fn send<W: Write>(&mut self, writer: &mut W, data: &[u8], limit: Option<usize>) -> io::Result<()> {
+        let left = limit.map_or(data.len(), |limit| limit.saturating_sub(self.sent));
+        writer.write_all(&data[..left.min(data.len())])?;
+        writer.flush()?;
+        self.sent += left.min(data.len());
+        if left < data.len() {
+            Err(io::ErrorKind::BrokenPipe.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Write response with faults applied
+    pub(crate) fn write<W: Write>(&mut self, writer: &mut W, response: &[u8]) -> io::Result<()> {
+        let mut data = response.to_vec();
+        let (mut stall, mut limit) = (None, None);
+        for fault in &self.faults {
+            match *fault {
+                Fault::Delay(duration) => thread::sleep(duration),
+                Fault::Stall { after, duration } => stall = Some((after, duration)),
+                Fault::BadBlockHeader => if data.len() > 1 && data[0] == b'#' {
+                    data[1] = b'x';
+                },
+                Fault::BadBlockSize => if data.len() > 1 && data[0] == b'#' {
+                    let digits = (data[1] as char).to_digit(10).unwrap_or(0) as usize;
+                    for b in data.iter_mut().skip(2).take(digits) {
+                        *b = b'x';
+                    }
+                },
+                Fault::NoTerminator => crate::remove_newline(&mut data),
+                Fault::DropAfter(num) => limit = Some(num),
+                Fault::Corrupt { rate, .. } => corrupt(self.rng.as_mut().unwrap(), &mut data, rate),
+            }
+        }
+        match stall {
+            Some((after, duration)) if after < data.len() => {
+                self.send(writer, &data[..after], limit)?;
+                thread::sleep(duration);
+                self.send(writer, &data[after..], limit)
+            },
+            _ => self.send(writer, &data, limit),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(injector: &mut Injector, response: &[u8]) -> (Vec<u8>, bool) {
+        let mut out = Vec::new();
+        let ok = injector.write(&mut out, response).is_ok();
+        (out, ok)
+    }
+
+    #[test]
+    fn block_faults() {
+        let mut injector = Injector::new(&[Fault::BadBlockHeader]);
+        assert_eq!(write(&mut injector, b"#13abc\n"), (b"#x3abc\n".to_vec(), true));
+        assert_eq!(write(&mut injector, b"text\n"), (b"text\n".to_vec(), true));
+
+        let mut injector = Injector::new(&[Fault::BadBlockSize, Fault::NoTerminator]);
+        assert_eq!(write(&mut injector, b"#210abcdefghij\r\n"), (b"#2xxabcdefghij".to_vec(), true));
+    }
+
+    /// Writer keeping each flushed part separately
+    #[derive(Default)]
+    struct Parts(Vec<Vec<u8>>, Vec<u8>);
+
+    impl Write for Parts {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            let part = std::mem::take(&mut self.1);
+            self.0.push(part);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stall() {
+        let mut injector = Injector::new(&[Fault::Stall { after: 2, duration: Duration::from_millis(1) }]);
+        let mut parts = Parts::default();
+        injector.write(&mut parts, b"abcd\n").unwrap();
+        injector.write(&mut parts, b"e\n").unwrap();
+        assert_eq!(parts.0, vec![b"ab".to_vec(), b"cd\n".to_vec(), b"e\n".to_vec()]);
+    }
+
+    #[test]
+    fn drop_after() {
+        let mut injector = Injector::new(&[Fault::DropAfter(6)]);
+        assert_eq!(write(&mut injector, b"abcd\n"), (b"abcd\n".to_vec(), true));
+        assert_eq!(write(&mut injector, b"efgh\n"), (b"e".to_vec(), false));
+        assert_eq!(write(&mut injector, b"ijkl\n"), (Vec::new(), false));
+
+        // Each connection has its own counter
+        let mut injector = Injector::new(&[Fault::DropAfter(6)]);
+        assert_eq!(write(&mut injector, b"abcd\n"), (b"abcd\n".to_vec(), true));
+    }
+
+    #[test]
+    fn corrupt_seed() {
+        let corrupt = |seed, rate| {
+            let mut injector = Injector::new(&[Fault::Corrupt { seed, rate }]);
+            let first = write(&mut injector, &[0; 64]).0;
+            (first, write(&mut injector, &[0; 64]).0)
+        };
+        let (data, next) = corrupt(1, 0.5);
+        assert_eq!((data.clone(), next.clone()), corrupt(1, 0.5));
+        assert_ne!(data, next);
+        assert_ne!(data, corrupt(2, 0.5).0);
+        let changed = data.iter().filter(|&&b| b != 0).count();
+        assert!(changed > 16 && changed < 48, "{}", changed);
+
+        assert!(corrupt(1, 0.0).0.iter().all(|&b| b == 0));
+        assert!(corrupt(1, 1.0).0.iter().all(|&b| b != 0));
+    }
+}
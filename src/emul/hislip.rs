@@ -0,0 +1,142 @@
+use std::io::{self};
+use std::cmp::min;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::transport::hislip::*;
+use super::Instrument;
+
+
+/// Maximum message size reported to clients, small enough to make them split messages
+pub const MAX_MESSAGE_SIZE: u64 = 64;
+/// Protocol version supported by emulator
+const VERSION: (u8, u8) = (1, 0);
+const SESSION_ID: u16 = 1;
+/// Error code of `Error` message for unrecognized message type
+const UNRECOGNIZED_MESSAGE_TYPE: u8 = 3;
+
+fn read_message(stream: &mut TcpStream) -> io::Result<(Header, Vec<u8>)> {
+    let header = Header::read_from(stream)?;
+    let payload = header.read_payload(stream)?;
+    Ok((header, payload))
+}
+
+fn closed(res: io::Result<()>) -> io::Result<()> {
+    match res {
+        Err(err) => match err.kind() {
+            io::ErrorKind::UnexpectedEof |
+            io::ErrorKind::ConnectionAborted |
+            io::ErrorKind::ConnectionReset |
+            io::ErrorKind::BrokenPipe => Ok(()),
+            _ => Err(err),
+        },
+        ok => ok,
+    }
+}
+
+/// Stand-in for HiSLIP server accepting single client session
+pub struct HislipEmulator {
+    listener: TcpListener,
+}
+
+impl HislipEmulator {
+    pub fn new(host: &str) -> io::Result<Self> {
+        Ok(HislipEmulator {
+            listener: TcpListener::bind((host, 0))?,
+        })
+    }
+
+    pub fn address(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn run(self) -> JoinHandle<io::Result<()>> {
+        thread::spawn(move || {
+            let mut sync = self.listener.incoming().next().unwrap()?;
+            let (header, _) = read_message(&mut sync)?;
+            if header.kind != INITIALIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "expected Initialize"));
+            }
+            let [major, minor, _, _] = header.param.to_be_bytes();
+            let (major, minor) = min((major, minor), VERSION);
+            let [id_hi, id_lo] = SESSION_ID.to_be_bytes();
+            let param = u32::from_be_bytes([major, minor, id_hi, id_lo]);
+            write_message(&mut sync, INITIALIZE_RESPONSE, 0, param, &[])?;
+
+            let mut asyn = self.listener.incoming().next().unwrap()?;
+            let (header, _) = read_message(&mut asyn)?;
+            if header.kind != ASYNC_INITIALIZE || header.param != SESSION_ID as u32 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "expected AsyncInitialize"));
+            }
+            write_message(&mut asyn, ASYNC_INITIALIZE_RESPONSE, 0, 0, &[])?;
+
+            let instrument = Arc::new(Mutex::new(Instrument::default()));
+            let writer = Arc::new(Mutex::new(asyn.try_clone()?));
+            let handle = {
+                let (instrument, writer) = (instrument.clone(), writer.clone());
+                thread::spawn(move || closed(serve_async(asyn, &instrument, &writer)))
+            };
+            let res = closed(serve_sync(sync, &instrument, &writer));
+            handle.join().unwrap().and(res)
+        })
+    }
+}
+
+fn serve_sync(mut stream: TcpStream, instrument: &Mutex<Instrument>, asyn: &Mutex<TcpStream>) -> io::Result<()> {
+    let mut input = Vec::new();
+    loop {
+        let (header, payload) = read_message(&mut stream)?;
+        match header.kind {
+            DATA => input.extend_from_slice(&payload),
+            DATA_END => {
+                input.extend_from_slice(&payload);
+                let mut output = Vec::new();
+                let mut instrument = instrument.lock().unwrap();
+                for line in input.split_inclusive(|&b| b == b'\n') {
+                    if let Some(response) = instrument.handle(line) {
+                        output.extend_from_slice(&response);
+                    }
+                }
+                input.clear();
+                if !output.is_empty() {
+                    write_message(&mut stream, DATA_END, 0, header.param, &output)?;
+                }
+                if let Some(stb) = instrument.take_service_request() {
+                    write_message(&mut *asyn.lock().unwrap(), ASYNC_SERVICE_REQUEST, stb, 0, &[])?;
+                }
+            },
+            DEVICE_CLEAR_COMPLETE => {
+                input.clear();
+                write_message(&mut stream, DEVICE_CLEAR_ACKNOWLEDGE, 0, 0, &[])?;
+            },
+            _ => write_message(&mut stream, ERROR, UNRECOGNIZED_MESSAGE_TYPE, 0, &[])?,
+        }
+    }
+}
+
+fn serve_async(mut stream: TcpStream, instrument: &Mutex<Instrument>, writer: &Mutex<TcpStream>) -> io::Result<()> {
+    let mut locked = false;
+    loop {
+        let (header, _) = read_message(&mut stream)?;
+        let (kind, control, payload) = match header.kind {
+            ASYNC_MAXIMUM_MESSAGE_SIZE => {
+                (ASYNC_MAXIMUM_MESSAGE_SIZE_RESPONSE, 0, MAX_MESSAGE_SIZE.to_be_bytes().to_vec())
+            },
+            ASYNC_LOCK => {
+                let control = match (header.control, locked) {
+                    (LOCK_REQUEST, _) => { locked = true; LOCK_SUCCESS },
+                    (_, true) => { locked = false; LOCK_SUCCESS },
+                    (_, false) => LOCK_ERROR,
+                };
+                (ASYNC_LOCK_RESPONSE, control, Vec::new())
+            },
+            ASYNC_DEVICE_CLEAR => (ASYNC_DEVICE_CLEAR_ACKNOWLEDGE, 0, Vec::new()),
+            ASYNC_STATUS_QUERY => {
+                (ASYNC_STATUS_RESPONSE, instrument.lock().unwrap().status_byte(), Vec::new())
+            },
+            _ => (ERROR, UNRECOGNIZED_MESSAGE_TYPE, Vec::new()),
+        };
+        write_message(&mut *writer.lock().unwrap(), kind, control, 0, &payload)?;
+    }
+}
@@ -0,0 +1,721 @@
+//! Emulated instruments for testing code built on `KsDevice` without hardware
+//!
+//! Available with `emulator` feature.
+
+use std::io::{prelude::*, self, BufReader, BufWriter};
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::collections::VecDeque;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration};
+
+use crate::ScpiError;
+
+mod vxi11;
+mod hislip;
+mod script;
+mod tree;
+mod fault;
+
+pub use vxi11::Vxi11Emulator;
+pub use hislip::HislipEmulator;
+pub use script::{Script, Rule, Reply};
+pub use tree::{CommandTree, Setting};
+pub use fault::{Fault};
+
+use tree::{Command, Pattern};
+
+use fault::Injector;
+
+/// Size of the block returned for `DUMP?` query
+pub const DUMP_SIZE: usize = 100_000;
+
+/// Definite length block followed by newline
+fn encode_block(content: &[u8]) -> Vec<u8> {
+    let size = content.len().to_string();
+    let mut data = format!("#{}{}", size.len(), size).into_bytes();
+    data.extend_from_slice(content);
+    data.push(b'\n');
+    data
+}
+
+fn dump() -> Vec<u8> {
+    encode_block(&(0..DUMP_SIZE).map(|i| i as u8).collect::<Vec<_>>())
+}
+
+/// Commands answered by instrument itself
+#[derive(Debug, Clone, Copy)]
+enum Builtin {
+    Identify,
+    Data,
+    DataNewline,
+    DataIndefinite,
+    Measure,
+    Output,
+    Dump,
+    NextError,
+    ClearStatus,
+    Reset,
+    OperationCompleteQuery,
+    OperationComplete,
+    EventStatus,
+    EventStatusEnableQuery,
+    EventStatusEnable,
+    ServiceRequestEnableQuery,
+    ServiceRequestEnable,
+    StatusByte,
+    OperationCondition,
+    QuestionableCondition,
+    OperationEvent,
+    QuestionableEvent,
+    OperationEnableQuery,
+    OperationEnable,
+    QuestionableEnableQuery,
+    QuestionableEnable,
+    LockRequest,
+    LockRelease,
+    PresetStatus,
+}
+
+/// Headers of built-in commands, query ones end with `?`
+const BUILTINS: &[(&str, Builtin)] = &[
+    ("*IDN?", Builtin::Identify),
+    ("DATA?", Builtin::Data),
+    ("DATA0:NL?", Builtin::DataNewline),
+    ("DATA0?", Builtin::DataIndefinite),
+    ("MEASure?", Builtin::Measure),
+    ("OUTPut[:STATe]?", Builtin::Output),
+    ("DUMP?", Builtin::Dump),
+    ("SYSTem:ERRor[:NEXT]?", Builtin::NextError),
+    ("*CLS", Builtin::ClearStatus),
+    ("*RST", Builtin::Reset),
+    ("*OPC?", Builtin::OperationCompleteQuery),
+    ("*OPC", Builtin::OperationComplete),
+    ("*ESR?", Builtin::EventStatus),
+    ("*ESE?", Builtin::EventStatusEnableQuery),
+    ("*ESE", Builtin::EventStatusEnable),
+    ("*SRE?", Builtin::ServiceRequestEnableQuery),
+    ("*SRE", Builtin::ServiceRequestEnable),
+    ("*STB?", Builtin::StatusByte),
+    ("STATus:OPERation:CONDition?", Builtin::OperationCondition),
+    ("STATus:QUEStionable:CONDition?", Builtin::QuestionableCondition),
+    ("STATus:OPERation[:EVENt]?", Builtin::OperationEvent),
+    ("STATus:QUEStionable[:EVENt]?", Builtin::QuestionableEvent),
+    ("STATus:OPERation:ENABle?", Builtin::OperationEnableQuery),
+    ("STATus:OPERation:ENABle", Builtin::OperationEnable),
+    ("STATus:QUEStionable:ENABle?", Builtin::QuestionableEnableQuery),
+    ("STATus:QUEStionable:ENABle", Builtin::QuestionableEnable),
+    ("SYSTem:LOCK:REQuest?", Builtin::LockRequest),
+    ("SYSTem:LOCK:RELease", Builtin::LockRelease),
+    ("STATus:PRESet", Builtin::PresetStatus),
+];
+
+/// Built-in command headers matched like command tree nodes
+struct Builtins {
+    patterns: Vec<(Pattern, bool, Builtin)>,
+}
+
+impl Default for Builtins {
+    fn default() -> Self {
+        let patterns = BUILTINS.iter().map(|&(header, builtin)| match header.strip_suffix('?') {
+            Some(header) => (Pattern::new(header), true, builtin),
+            None => (Pattern::new(header), false, builtin),
+        }).collect();
+        Self { patterns }
+    }
+}
+
+impl Builtins {
+    fn find(&self, cmd: &Command) -> Option<Builtin> {
+        self.patterns.iter()
+        .find(|(pattern, query, _)| *query == cmd.query && pattern.matches(&cmd.tokens).is_some())
+        .map(|&(_, _, builtin)| builtin)
+    }
+}
+
+/// Simulated instrument state shared by emulated transports
+#[derive(Default)]
+pub(crate) struct Instrument {
+    errors: VecDeque<ScpiError>,
+    /// Standard event status register
+    esr: u8,
+    /// Standard event status enable register
+    ese: u8,
+    /// Service request enable register
+    sre: u8,
+    /// Operation and questionable event registers, conditions are always clear
+    oper: u16,
+    ques: u16,
+    oper_enable: u16,
+    ques_enable: u16,
+    /// Session that was granted lock with `SYST:LOCK:REQ?`
+    lock_owner: Option<usize>,
+    script: Option<Arc<Mutex<Script>>>,
+    /// Settings answered before built-in commands
+    tree: CommandTree,
+    builtins: Builtins,
+    /// Service request was raised and not yet cleared
+    requested: bool,
+    service_request: Option<u8>,
+}
+
+fn parse_register<T: FromStr + Default>(param: &str) -> T {
+    param.parse().unwrap_or_default()
+}
+
+fn register_response<T: Display>(value: T) -> Option<Cow<'static, [u8]>> {
+    Some(format!("{}\n", value).into_bytes().into())
+}
+
+impl Instrument {
+    /// Instrument answering by rules of `script` before built-in commands
+    pub(crate) fn with_script(script: Arc<Mutex<Script>>) -> Self {
+        Self { script: Some(script), ..Self::default() }
+    }
+
+    /// Replace command tree of settings, their values are reset
+    pub(crate) fn set_tree(&mut self, tree: CommandTree) {
+        self.tree = tree;
+    }
+
+    fn push_error(&mut self, code: i32, message: &str) {
+        self.errors.push_back(ScpiError { code, message: String::from(message) });
+    }
+
+    /// Status byte with MSS bit
+    pub(crate) fn status_byte(&self) -> u8 {
+        let mut stb = 0;
+        if !self.errors.is_empty() {
+            stb |= 0x04;
+        }
+        if self.ques & self.ques_enable != 0 {
+            stb |= 0x08;
+        }
+        if self.oper & self.oper_enable != 0 {
+            stb |= 0x80;
+        }
+        if self.esr & self.ese != 0 {
+            stb |= 0x20;
+        }
+        if stb & self.sre != 0 {
+            stb |= 0x40;
+        }
+        stb
+    }
+
+    /// Status byte of service request raised since the last call
+    pub(crate) fn take_service_request(&mut self) -> Option<u8> {
+        self.service_request.take()
+    }
+
+    /// Handle single command line returning response if there is any
+    pub(crate) fn handle(&mut self, cmd: &[u8]) -> Option<Cow<'static, [u8]>> {
+        self.handle_from(0, cmd)
+    }
+
+    /// Handle command line received by one of several sessions sharing the instrument
+    pub(crate) fn handle_from(&mut self, session: usize, cmd: &[u8]) -> Option<Cow<'static, [u8]>> {
+        let response = self.respond(session, cmd);
+        let stb = self.status_byte();
+        if stb & 0x40 == 0 {
+            self.requested = false;
+        } else if !self.requested {
+            self.requested = true;
+            self.service_request = Some(stb);
+        }
+        response
+    }
+
+    /// Drop lock held by closed session
+    pub(crate) fn release(&mut self, session: usize) {
+        if self.lock_owner == Some(session) {
+            self.lock_owner = None;
+        }
+    }
+
+    /// Respond to message line, responses of compound message are joined by `;`
+    fn respond(&mut self, session: usize, line: &[u8]) -> Option<Cow<'static, [u8]>> {
+        if let Some(ref script) = self.script {
+            let text = String::from_utf8_lossy(line);
+            if let Some(response) = script.lock().unwrap().handle(text.trim_end()) {
+                return response.map(Cow::from);
+            }
+        }
+        let mut path = Vec::new();
+        let mut responses = split_units(line).into_iter()
+        .filter_map(|unit| self.respond_unit(session, unit, &mut path))
+        .collect::<Vec<_>>();
+        if responses.len() <= 1 {
+            return responses.pop();
+        }
+        let mut joined = Vec::new();
+        for response in responses {
+            if !joined.is_empty() {
+                joined.push(b';');
+            }
+            let mut response = response.into_owned();
+            crate::remove_newline(&mut response);
+            joined.extend_from_slice(&response);
+        }
+        joined.push(b'\n');
+        Some(joined.into())
+    }
+
+    /// Respond to single command, `path` is the header path of compound message
+    ///
+    /// Settings of command tree take precedence over built-in commands with the same header.
+    fn respond_unit(&mut self, session: usize, unit: &[u8], path: &mut Vec<String>) -> Option<Cow<'static, [u8]>> {
+        let res = Command::parse(&String::from_utf8_lossy(unit), path)
+        .and_then(|cmd| {
+            let res = match self.tree.handle_command(&cmd) {
+                Some(res) => res.map(|response| response.map(|text| format!("{}\n", text).into_bytes().into())),
+                None => match self.builtins.find(&cmd) {
+                    Some(builtin) => Ok(self.respond_builtin(session, builtin, &cmd.param)),
+                    None => Err(tree::undefined_header()),
+                },
+            };
+            cmd.update_path(path);
+            res
+        });
+        match res {
+            Ok(response) => response,
+            Err(error) => {
+                self.errors.push_back(error);
+                None
+            },
+        }
+    }
+
+    fn respond_builtin(&mut self, session: usize, builtin: Builtin, param: &str) -> Option<Cow<'static, [u8]>> {
+        match builtin {
+            Builtin::Identify => Some(b"Emulator\r\n"[..].into()),
+            Builtin::Data => Some(b"#14\0\xff\n\x80\r\n"[..].into()),
+            Builtin::DataNewline => Some(b"#0\x01\n\x02\n"[..].into()),
+            Builtin::DataIndefinite => Some(b"#0\0\xff\r\x80\n"[..].into()),
+            Builtin::Measure => Some(b"+1.50000000E+00\n"[..].into()),
+            Builtin::Output => Some(b"1\n"[..].into()),
+            Builtin::Dump => Some(dump().into()),
+            Builtin::NextError => match self.errors.pop_front() {
+                Some(error) => Some(format!("{}\n", error).into_bytes().into()),
+                None => Some(b"+0,\"No error\"\n"[..].into()),
+            },
+            Builtin::ClearStatus => {
+                self.errors.clear();
+                self.esr = 0;
+                self.oper = 0;
+                self.ques = 0;
+                None
+            },
+            Builtin::Reset => {
+                self.tree.reset();
+                None
+            },
+            Builtin::OperationCompleteQuery => {
+                self.esr |= 0x01;
+                Some(b"1\n"[..].into())
+            },
+            Builtin::OperationComplete => {
+                self.esr |= 0x01;
+                None
+            },
+            Builtin::EventStatus => register_response(std::mem::take(&mut self.esr)),
+            Builtin::EventStatusEnableQuery => register_response(self.ese),
+            Builtin::EventStatusEnable => {
+                self.ese = parse_register(param);
+                None
+            },
+            Builtin::ServiceRequestEnableQuery => register_response(self.sre),
+            Builtin::ServiceRequestEnable => {
+                self.sre = parse_register(param);
+                None
+            },
+            Builtin::StatusByte => register_response(self.status_byte()),
+            Builtin::OperationCondition | Builtin::QuestionableCondition => register_response(0),
+            Builtin::OperationEvent => register_response(std::mem::take(&mut self.oper)),
+            Builtin::QuestionableEvent => register_response(std::mem::take(&mut self.ques)),
+            Builtin::OperationEnableQuery => register_response(self.oper_enable),
+            Builtin::OperationEnable => {
+                self.oper_enable = parse_register(param);
+                None
+            },
+            Builtin::QuestionableEnableQuery => register_response(self.ques_enable),
+            Builtin::QuestionableEnable => {
+                self.ques_enable = parse_register(param);
+                None
+            },
+            Builtin::LockRequest => match self.lock_owner {
+                Some(owner) if owner != session => Some(b"0\n"[..].into()),
+                _ => {
+                    self.lock_owner = Some(session);
+                    Some(b"1\n"[..].into())
+                },
+            },
+            Builtin::LockRelease => {
+                if self.lock_owner == Some(session) {
+                    self.lock_owner = None;
+                } else {
+                    self.push_error(-221, "Settings conflict");
+                }
+                None
+            },
+            Builtin::PresetStatus => {
+                self.oper_enable = 0;
+                self.ques_enable = 0;
+                None
+            },
+        }
+    }
+}
+
+/// Split message into commands by `;` outside of quoted strings
+fn split_units(line: &[u8]) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    let (mut start, mut quote) = (0, None);
+    for (pos, &b) in line.iter().enumerate() {
+        match quote {
+            Some(q) if b == q => quote = None,
+            Some(_) => (),
+            None => match b {
+                b'"' | b'\'' => quote = Some(b),
+                b';' => {
+                    units.push(&line[start..pos]);
+                    start = pos + 1;
+                },
+                _ => (),
+            },
+        }
+    }
+    units.push(&line[start..]);
+    units
+}
+
+/// Interval of checking stop flag by emulator threads
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Commands received by emulator in order of arrival, clones share the same log
+#[derive(Clone, Debug, Default)]
+pub struct CommandLog {
+    commands: Arc<Mutex<Vec<String>>>,
+}
+
+impl CommandLog {
+    fn push(&self, line: &[u8]) {
+        let mut line = line.to_vec();
+        crate::remove_newline(&mut line);
+        self.commands.lock().unwrap().push(String::from_utf8_lossy(&line).into_owned());
+    }
+
+    /// Received commands without terminators
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    pub fn contains(&self, cmd: &str) -> bool {
+        self.commands.lock().unwrap().iter().any(|c| c == cmd)
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.commands.lock().unwrap().clear();
+    }
+}
+
+/// Running emulator, it is not stopped on drop
+pub struct EmulatorHandle {
+    thread: JoinHandle<io::Result<()>>,
+    stop: Arc<AtomicBool>,
+    log: CommandLog,
+}
+
+impl EmulatorHandle {
+    /// Close connections and stop accepting new ones, returns without waiting for that
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Wait for emulator to finish serving
+    pub fn join(self) -> thread::Result<io::Result<()>> {
+        self.thread.join()
+    }
+
+    pub fn log(&self) -> &CommandLog {
+        &self.log
+    }
+}
+
+/// Raw SCPI socket instrument emulator
+pub struct Emulator {
+    listener: TcpListener,
+    control: Option<TcpListener>,
+    log: CommandLog,
+    script: Option<Arc<Mutex<Script>>>,
+    tree: CommandTree,
+    faults: Vec<Fault>,
+}
+
+
+impl Emulator {
+    pub fn new(addr: (&str, u16)) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(Emulator {
+            listener,
+            control: None,
+            log: CommandLog::default(),
+            script: None,
+            tree: CommandTree::default(),
+            faults: Vec::new(),
+        })
+    }
+
+    /// Answer by rules of `script` before built-in commands, rule state is shared by all connections
+    pub fn with_script(mut self, script: Script) -> Self {
+        self.script = Some(Arc::new(Mutex::new(script)));
+        self
+    }
+
+    /// Remember settings of `tree`, they take precedence over built-in commands
+    pub fn with_tree(mut self, tree: CommandTree) -> Self {
+        self.tree = tree;
+        self
+    }
+
+    /// Apply `fault` to every response, faults are applied in order they were added
+    pub fn with_fault(mut self, fault: Fault) -> Self {
+        self.faults.push(fault);
+        self
+    }
+
+    /// Also listen for socket control connections answering `DCL`
+    pub fn with_control(mut self, addr: (&str, u16)) -> io::Result<Self> {
+        self.control = Some(TcpListener::bind(addr)?);
+        Ok(self)
+    }
+
+    pub fn address(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn control_address(&self) -> Option<io::Result<SocketAddr>> {
+        self.control.as_ref().map(|control| control.local_addr())
+    }
+
+    /// Log of commands received over all connections
+    pub fn log(&self) -> &CommandLog {
+        &self.log
+    }
+
+    /// Serve single connection
+    pub fn run(self) -> EmulatorHandle {
+        self.run_sessions(1)
+    }
+
+    /// Serve connections until stopped
+    pub fn run_forever(self) -> EmulatorHandle {
+        self.run_sessions(usize::MAX)
+    }
+
+    /// Accept given number of connections serving them concurrently
+    ///
+    /// Every connection is a separate session with its own input and lock ownership,
+    /// while instrument state such as registers and error queue is shared by all of them.
+    /// Emulator finishes when all accepted sessions are closed.
+    pub fn run_sessions(self, count: usize) -> EmulatorHandle {
+        let Emulator { listener, control, log, script, tree, faults } = self;
+        let stop = Arc::new(AtomicBool::new(false));
+        if let Some(control) = control {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                accept_until(&control, &stop, usize::MAX, |stream| {
+                    let _ = serve_control(stream, &stop);
+                    Ok(())
+                })
+            });
+        }
+        let thread = {
+            let (stop, log) = (stop.clone(), log.clone());
+            let mut instrument = match script {
+                Some(script) => Instrument::with_script(script),
+                None => Instrument::default(),
+            };
+            instrument.set_tree(tree);
+            let instrument = Arc::new(Mutex::new(instrument));
+            thread::spawn(move || {
+                let mut sessions = Vec::new();
+                let res = accept_until(&listener, &stop, count, |stream| {
+                    let (session, instrument, stop, log) = (sessions.len(), instrument.clone(), stop.clone(), log.clone());
+                    let injector = Injector::new(&faults);
+                    sessions.push(thread::spawn(move || {
+                        let res = serve(stream, session, &instrument, injector, &stop, &log);
+                        instrument.lock().unwrap().release(session);
+                        res
+                    }));
+                    Ok(())
+                });
+                sessions.into_iter().fold(res, |res, session| {
+                    let other = session.join()
+                    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "session thread panicked")));
+                    res.and(other)
+                })
+            })
+        };
+        EmulatorHandle { thread, stop, log }
+    }
+}
+
+/// Accept up to `count` connections passing them to `serve` until `stop` is set
+fn accept_until<F>(listener: &TcpListener, stop: &AtomicBool, count: usize, mut serve: F) -> io::Result<()>
+where F: FnMut(TcpStream) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut served = 0;
+    while served < count && !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                serve(stream)?;
+                served += 1;
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Read line waiting for it until `stop` is set, then empty line is returned like on close
+fn read_line<R: BufRead>(reader: &mut R, buf: &mut Vec<u8>, stop: &AtomicBool) -> io::Result<usize> {
+    loop {
+        match reader.read_until(b'\n', buf) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                if stop.load(Ordering::SeqCst) {
+                    break Ok(0);
+                }
+            },
+            res => break res,
+        }
+    }
+}
+
+/// Serve socket control connection, every `DCL` is echoed back when done
+fn serve_control(stream: TcpStream, stop: &AtomicBool) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut buf = Vec::new();
+        if read_line(&mut reader, &mut buf, stop)? == 0 {
+            break Ok(());
+        }
+        if String::from_utf8_lossy(&buf).trim() == "DCL" {
+            writer.write_all(b"DCL\n")?;
+        }
+    }
+}
+
+/// Serve single session, `DROP` command closes it from the instrument side
+fn serve(
+    stream: TcpStream, session: usize, instrument: &Mutex<Instrument>, mut injector: Injector,
+    stop: &AtomicBool, log: &CommandLog,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let mut buf = Vec::new();
+
+        match read_line(&mut reader, &mut buf, stop)
+        .and_then(|num| {
+            if num == 0 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            log.push(&buf);
+            if buf.starts_with(b"DROP") {
+                Err(io::ErrorKind::BrokenPipe.into())
+            } else {
+                Ok(())
+            }
+        })
+        .map(|()| instrument.lock().unwrap().handle_from(session, &buf))
+        .and_then(|response| match response {
+            Some(response) => injector.write(&mut writer, &response),
+            None => Ok(()),
+        }) {
+            Ok(_) => (),
+            Err(err) => match err.kind() {
+                 io::ErrorKind::ConnectionAborted |
+                 io::ErrorKind::ConnectionReset |
+                 io::ErrorKind::BrokenPipe => break Ok(()),
+                _ => break Err(err),
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle_from(instrument: &mut Instrument, session: usize, message: &str) -> Option<String> {
+        instrument.handle_from(session, message.as_bytes())
+        .map(|response| String::from_utf8_lossy(&response).into_owned())
+    }
+
+    fn handle(instrument: &mut Instrument, message: &str) -> Option<String> {
+        handle_from(instrument, 0, message)
+    }
+
+    #[test]
+    fn builtins() {
+        let mut instrument = Instrument::default();
+        for cmd in &["MEAS?", ":MEASure?", "meas?"] {
+            assert_eq!(handle(&mut instrument, cmd).as_deref(), Some("+1.50000000E+00\n"));
+        }
+        assert_eq!(handle(&mut instrument, "MEAS?XYZ"), None);
+        assert_eq!(handle(&mut instrument, "*IDNX?"), None);
+        assert_eq!(handle(&mut instrument, "syst:err:next?").as_deref(), Some("-113,\"Undefined header\"\n"));
+        assert_eq!(handle(&mut instrument, "SYSTem:ERRor?").as_deref(), Some("-113,\"Undefined header\"\n"));
+        assert_eq!(handle(&mut instrument, "SYST:ERR?").as_deref(), Some("+0,\"No error\"\n"));
+        assert_eq!(handle(&mut instrument, "STAT:OPER:ENAB 5;ENAB?;*ESE?;ENAB?").as_deref(), Some("5;0;5\n"));
+        assert_eq!(handle(&mut instrument, "outp?").as_deref(), Some("1\n"));
+
+        instrument.set_tree(CommandTree::new().boolean("OUTPut#[:STATe]", false));
+        assert_eq!(handle(&mut instrument, "OUTP?").as_deref(), Some("0\n"));
+        assert_eq!(handle(&mut instrument, ":OUTPut:STATe ON;:outp1?").as_deref(), Some("1\n"));
+    }
+
+    #[test]
+    fn sessions() {
+        let mut instrument = Instrument::default();
+
+        // Registers are shared
+        assert_eq!(handle_from(&mut instrument, 0, "*ESE 1"), None);
+        assert_eq!(handle_from(&mut instrument, 1, "*ESE?").as_deref(), Some("1\n"));
+
+        // Lock is owned by session
+        assert_eq!(handle_from(&mut instrument, 0, "SYST:LOCK:REQ?").as_deref(), Some("1\n"));
+        assert_eq!(handle_from(&mut instrument, 1, "SYST:LOCK:REQ?").as_deref(), Some("0\n"));
+        assert_eq!(handle_from(&mut instrument, 1, "SYST:LOCK:REL"), None);
+        assert_eq!(handle_from(&mut instrument, 0, "SYST:ERR?").as_deref(), Some("-221,\"Settings conflict\"\n"));
+        assert_eq!(handle_from(&mut instrument, 1, "SYST:LOCK:REQ?").as_deref(), Some("0\n"));
+
+        instrument.release(1);
+        assert_eq!(handle_from(&mut instrument, 1, "SYST:LOCK:REQ?").as_deref(), Some("0\n"));
+        instrument.release(0);
+        assert_eq!(handle_from(&mut instrument, 1, "SYST:LOCK:REQ?").as_deref(), Some("1\n"));
+    }
+}
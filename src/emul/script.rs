@@ -0,0 +1,203 @@
+use regex::Regex;
+
+use super::encode_block;
+
+
+/// Data sent back for command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Set command without response
+    None,
+    /// Text response, newline is appended
+    Text(String),
+    /// Definite length binary block
+    Block(Vec<u8>),
+    /// Bytes sent as is
+    Raw(Vec<u8>),
+}
+
+impl Reply {
+    fn encode(self) -> Option<Vec<u8>> {
+        match self {
+            Reply::None => None,
+            Reply::Text(text) => Some(format!("{}\n", text).into_bytes()),
+            Reply::Block(data) => Some(encode_block(&data)),
+            Reply::Raw(data) => Some(data),
+        }
+    }
+}
+
+enum Pattern {
+    /// Header compared ignoring case
+    Exact(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn matches(&self, header: &str) -> bool {
+        match *self {
+            Pattern::Exact(ref text) => text.eq_ignore_ascii_case(header),
+            Pattern::Regex(ref re) => re.is_match(header),
+        }
+    }
+}
+
+enum Action {
+    Reply(Reply),
+    /// Called with the whole command including its parameters
+    Func(Box<dyn FnMut(&str) -> Reply + Send>),
+}
+
+/// Command header pattern with sequence of responses to it
+///
+/// Each response method appends to the sequence, so the first matching command
+/// gets the first response, the next one gets the second and so on.
+/// The last response is repeated after the sequence is over.
+pub struct Rule {
+    pattern: Pattern,
+    actions: Vec<Action>,
+    calls: usize,
+}
+
+impl Rule {
+    fn new(pattern: Pattern) -> Self {
+        Self { pattern, actions: Vec::new(), calls: 0 }
+    }
+
+    /// Match header exactly ignoring case, e.g. `*IDN?`
+    pub fn exact(header: &str) -> Self {
+        Self::new(Pattern::Exact(String::from(header)))
+    }
+
+    /// Match header against regular expression, use `(?i)` to ignore case
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        Regex::new(pattern).map(|re| Self::new(Pattern::Regex(re)))
+    }
+
+    pub fn reply(mut self, reply: Reply) -> Self {
+        self.actions.push(Action::Reply(reply));
+        self
+    }
+
+    pub fn text(self, text: &str) -> Self {
+        self.reply(Reply::Text(String::from(text)))
+    }
+
+    pub fn block(self, data: &[u8]) -> Self {
+        self.reply(Reply::Block(data.to_vec()))
+    }
+
+    pub fn no_response(self) -> Self {
+        self.reply(Reply::None)
+    }
+
+    /// Compute response from the received command
+    pub fn func<F>(mut self, f: F) -> Self
+    where F: FnMut(&str) -> Reply + Send + 'static {
+        self.actions.push(Action::Func(Box::new(f)));
+        self
+    }
+
+    /// Number of commands matched so far
+    pub fn calls(&self) -> usize {
+        self.calls
+    }
+
+    fn call(&mut self, cmd: &str) -> Reply {
+        let index = self.calls.min(self.actions.len().saturating_sub(1));
+        self.calls += 1;
+        match self.actions.get_mut(index) {
+            Some(Action::Reply(reply)) => reply.clone(),
+            Some(Action::Func(f)) => f(cmd),
+            None => Reply::None,
+        }
+    }
+}
+
+/// Table of rules checked in order before built-in commands of emulator
+#[derive(Default)]
+pub struct Script {
+    rules: Vec<Rule>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.push(rule);
+        self
+    }
+
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Response of the first rule matching command, `None` if there is no such rule
+    pub(crate) fn handle(&mut self, cmd: &str) -> Option<Option<Vec<u8>>> {
+        let header = cmd.split_whitespace().next().unwrap_or("");
+        self.rules.iter_mut()
+        .find(|rule| rule.pattern.matches(header))
+        .map(|rule| rule.call(cmd).encode())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence() {
+        let mut script = Script::new()
+        .rule(Rule::exact("*idn?").text("Acme"))
+        .rule(Rule::regex(r"^MEAS:VOLT").unwrap().text("+1.0").text("+2.0"))
+        .rule(Rule::regex(r"^CONF").unwrap().no_response().func(|cmd| Reply::Text(cmd.to_lowercase())));
+
+        assert_eq!(script.handle("*IDN?"), Some(Some(b"Acme\n".to_vec())));
+        assert_eq!(script.handle("MEAS:VOLT:DC?"), Some(Some(b"+1.0\n".to_vec())));
+        assert_eq!(script.handle("MEAS:VOLT:DC?"), Some(Some(b"+2.0\n".to_vec())));
+        assert_eq!(script.handle("MEAS:VOLT:DC?"), Some(Some(b"+2.0\n".to_vec())));
+        assert_eq!(script.handle("CONF:VOLT 10"), Some(None));
+        assert_eq!(script.handle("CONF:VOLT 10"), Some(Some(b"conf:volt 10\n".to_vec())));
+        assert_eq!(script.handle("MEAS:CURR?"), None);
+        assert_eq!(script.rules()[1].calls(), 3);
+    }
+
+    #[test]
+    fn regex_rules() {
+        let mut script = Script::new()
+        .rule(Rule::regex(r"^VOLT\?$").unwrap().text("exact"))
+        .rule(Rule::regex(r"(?i)^volt").unwrap().text("any case"))
+        .rule(Rule::regex(r"10").unwrap().text("parameter"));
+
+        assert_eq!(script.handle("VOLT?"), Some(Some(b"exact\n".to_vec())));
+        assert_eq!(script.handle("volt?"), Some(Some(b"any case\n".to_vec())));
+        assert_eq!(script.handle("VOLT:RANG 10"), Some(Some(b"any case\n".to_vec())));
+        // Only header is matched
+        assert_eq!(script.handle("CURR 10"), None);
+        assert_eq!(script.rules().iter().map(Rule::calls).collect::<Vec<_>>(), vec![1, 2, 0]);
+        assert!(Rule::regex(r"VOLT(").is_err());
+    }
+
+    #[test]
+    fn sequence_exhaustion() {
+        let mut script = Script::new()
+        .rule(Rule::exact("DATA?").block(b"ab").reply(Reply::Raw(b"#0ab\n".to_vec())))
+        .rule(Rule::exact("*RST"));
+
+        assert_eq!(script.handle("DATA?"), Some(Some(b"#12ab\n".to_vec())));
+        for _ in 0..3 {
+            assert_eq!(script.handle("DATA?"), Some(Some(b"#0ab\n".to_vec())));
+        }
+        assert_eq!(script.rules()[0].calls(), 4);
+        // Rule without responses swallows command
+        assert_eq!(script.handle("*RST"), Some(None));
+        assert_eq!(script.handle("*RST"), Some(None));
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+
+use crate::ScpiError;
+
+
+fn error(code: i32, message: &str) -> ScpiError {
+    ScpiError { code, message: String::from(message) }
+}
+
+/// Keyword of node pattern, e.g. `VOLTage` or optional `[SOURce#]`
+#[derive(Debug, Clone)]
+struct Keyword {
+    long: String,
+    short: String,
+    optional: bool,
+    /// Numeric suffix is allowed, `1` when omitted
+    suffix: bool,
+}
+
+impl Keyword {
+    fn new(name: &str, optional: bool) -> Self {
+        let (name, suffix) = match name.strip_suffix('#') {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let short = name.chars().take_while(|c| !c.is_ascii_lowercase()).collect::<String>();
+        Self { long: name.to_ascii_uppercase(), short, optional, suffix }
+    }
+
+    /// Numeric suffix if header keyword matches either form
+    fn matches(&self, token: &str) -> Option<u32> {
+        // Keyword may end with digits itself, e.g. `DATA0`
+        if token.eq_ignore_ascii_case(&self.short) || token.eq_ignore_ascii_case(&self.long) {
+            return Some(1);
+        }
+        let name = token.trim_end_matches(|c: char| c.is_ascii_digit());
+        let digits = &token[name.len()..];
+        if !(name.eq_ignore_ascii_case(&self.short) || name.eq_ignore_ascii_case(&self.long)) {
+            return None;
+        }
+        match (digits, self.suffix) {
+            ("", _) => Some(1),
+            (digits, true) => digits.parse().ok(),
+            (_, false) => None,
+        }
+    }
+}
+
+/// Split pattern like `[SOURce#]:VOLTage[:LEVel]` into keywords
+fn parse_pattern(pattern: &str) -> Vec<Keyword> {
+    let mut keywords = Vec::new();
+    let mut name = String::new();
+    let mut optional = false;
+    for c in pattern.chars().chain(Some(':')) {
+        match c {
+            ':' | '[' | ']' => {
+                if !name.is_empty() {
+                    keywords.push(Keyword::new(&name, optional));
+                    name.clear();
+                }
+                match c {
+                    '[' => optional = true,
+                    ']' => optional = false,
+                    _ => (),
+                }
+            },
+            c => name.push(c),
+        }
+    }
+    keywords
+}
+
+/// Match header keywords skipping optional ones, suffixes of matched keywords are collected
+fn match_keywords(keywords: &[Keyword], tokens: &[&str], suffixes: &mut Vec<u32>) -> bool {
+    let (keyword, rest) = match keywords.split_first() {
+        Some(split) => split,
+        None => return tokens.is_empty(),
+    };
+    let len = suffixes.len();
+    if let Some((token, tail)) = tokens.split_first() {
+        if let Some(n) = keyword.matches(token) {
+            if keyword.suffix {
+                suffixes.push(n);
+            }
+            if match_keywords(rest, tail, suffixes) {
+                return true;
+            }
+            suffixes.truncate(len);
+        }
+    }
+    if keyword.optional {
+        if keyword.suffix {
+            suffixes.push(1);
+        }
+        if match_keywords(rest, tokens, suffixes) {
+            return true;
+        }
+        suffixes.truncate(len);
+    }
+    false
+}
+
+/// Header pattern in the notation of instrument manuals, e.g. `[SOURce#]:VOLTage[:LEVel]`
+pub(crate) struct Pattern {
+    keywords: Vec<Keyword>,
+}
+
+impl Pattern {
+    pub(crate) fn new(pattern: &str) -> Self {
+        Self { keywords: parse_pattern(pattern) }
+    }
+
+    /// Numeric suffixes of header keywords if they match the pattern
+    pub(crate) fn matches(&self, tokens: &[String]) -> Option<Vec<u32>> {
+        let tokens = tokens.iter().map(String::as_str).collect::<Vec<_>>();
+        let mut suffixes = Vec::new();
+        if match_keywords(&self.keywords, &tokens, &mut suffixes) {
+            Some(suffixes)
+        } else {
+            None
+        }
+    }
+}
+
+/// Single command of compound message split into header keywords, query flag and parameter
+pub(crate) struct Command {
+    pub(crate) tokens: Vec<String>,
+    pub(crate) query: bool,
+    pub(crate) param: String,
+    /// Common command like `*CLS`, it neither uses nor changes header path
+    common: bool,
+}
+
+impl Command {
+    /// Parse command, `path` is the header path of previous command in the same message,
+    /// it is prepended to header without leading `:`
+    pub(crate) fn parse(cmd: &str, path: &[String]) -> Result<Self, ScpiError> {
+        let cmd = cmd.trim();
+        let (header, param) = match cmd.find(char::is_whitespace) {
+            Some(pos) => (&cmd[..pos], cmd[pos..].trim()),
+            None => (cmd, ""),
+        };
+        let (header, query) = match header.strip_suffix('?') {
+            Some(header) => (header, true),
+            None => (header, false),
+        };
+        let common = header.starts_with('*');
+        let (header, path) = match header.strip_prefix(':') {
+            Some(header) => (header, &[][..]),
+            None if common => (header, &[][..]),
+            None => (header, path),
+        };
+        let tokens = path.iter().cloned()
+        .chain(header.split(':').map(String::from))
+        .collect::<Vec<_>>();
+        if tokens.iter().any(String::is_empty) {
+            return Err(undefined_header());
+        }
+        Ok(Self { tokens, query, param: String::from(param), common })
+    }
+
+    /// Set header path for the next command of the same message
+    pub(crate) fn update_path(&self, path: &mut Vec<String>) {
+        if !self.common {
+            *path = self.tokens[..self.tokens.len() - 1].to_vec();
+        }
+    }
+}
+
+pub(crate) fn undefined_header() -> ScpiError {
+    error(-113, "Undefined header")
+}
+
+/// Value of settable node
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Setting {
+    Number { default: f64, min: f64, max: f64 },
+    Bool { default: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    Number(f64),
+    Bool(bool),
+}
+
+/// NR3 form used by instruments, e.g. `+5.00000000E+00`
+///
+/// Non-finite values are given by SCPI codes `9.91E+37` for NaN and `9.9E+37` for infinity.
+fn format_number(x: f64) -> String {
+    let x = match x {
+        x if x.is_nan() => 9.91e37,
+        x if x.is_infinite() => 9.9e37f64.copysign(x),
+        x => x,
+    };
+    let text = format!("{:.8E}", x);
+    let (mantissa, exp) = text.split_at(text.find('E').unwrap());
+    let exp = exp[1..].parse::<i32>().unwrap();
+    let sign = if mantissa.starts_with('-') { "" } else { "+" };
+    format!("{}{}E{:+03}", sign, mantissa, exp)
+}
+
+/// Parameter keyword with both forms, e.g. `MAXimum`
+fn is_keyword(param: &str, short: &str, long: &str) -> bool {
+    param.eq_ignore_ascii_case(short) || param.eq_ignore_ascii_case(long)
+}
+
+impl Setting {
+    fn default_value(&self) -> Value {
+        match *self {
+            Setting::Number { default, .. } => Value::Number(default),
+            Setting::Bool { default } => Value::Bool(default),
+        }
+    }
+
+    /// Value of `MIN`, `MAX` or `DEF` parameter
+    fn limit(&self, param: &str) -> Option<Value> {
+        match *self {
+            Setting::Number { min, .. } if is_keyword(param, "MIN", "MINIMUM") => Some(Value::Number(min)),
+            Setting::Number { max, .. } if is_keyword(param, "MAX", "MAXIMUM") => Some(Value::Number(max)),
+            _ if is_keyword(param, "DEF", "DEFAULT") => Some(self.default_value()),
+            _ => None,
+        }
+    }
+
+    fn parse(&self, param: &str) -> Result<Value, ScpiError> {
+        if let Some(value) = self.limit(param) {
+            return Ok(value);
+        }
+        match *self {
+            Setting::Number { min, max, .. } => {
+                let x = param.parse::<f64>().ok()
+                .filter(|x| x.is_finite())
+                .ok_or_else(|| error(-104, "Data type error"))?;
+                if x < min || x > max {
+                    return Err(error(-222, "Data out of range"));
+                }
+                Ok(Value::Number(x))
+            },
+            Setting::Bool { .. } => {
+                if param.eq_ignore_ascii_case("ON") {
+                    Ok(Value::Bool(true))
+                } else if param.eq_ignore_ascii_case("OFF") {
+                    Ok(Value::Bool(false))
+                } else {
+                    param.parse::<f64>().ok()
+                    .filter(|x| x.is_finite())
+                    .map(|x| Value::Bool(x.round() != 0.0))
+                    .ok_or_else(|| error(-104, "Data type error"))
+                }
+            },
+        }
+    }
+}
+
+impl Value {
+    fn format(&self) -> String {
+        match *self {
+            Value::Number(x) => format_number(x),
+            Value::Bool(b) => String::from(if b { "1" } else { "0" }),
+        }
+    }
+}
+
+struct Node {
+    pattern: Pattern,
+    setting: Setting,
+}
+
+/// SCPI command tree of settings remembered by emulated instrument
+///
+/// Nodes are given by patterns in the notation of instrument manuals:
+/// `[SOURce#]:VOLTage[:LEVel]` accepts `VOLT`, `SOUR2:VOLTAGE:LEV` and so on.
+/// Each numeric suffix combination keeps its own value.
+/// Setting is changed by `HEADER <value>` and read back by `HEADER?`,
+/// `MIN`, `MAX` and `DEF` are accepted both as value and as query parameter.
+#[derive(Default)]
+pub struct CommandTree {
+    nodes: Vec<Node>,
+    values: HashMap<(usize, Vec<u32>), Value>,
+}
+
+impl CommandTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn setting(mut self, pattern: &str, setting: Setting) -> Self {
+        self.nodes.push(Node { pattern: Pattern::new(pattern), setting });
+        self
+    }
+
+    /// Numeric setting limited to `min..=max`
+    pub fn number(self, pattern: &str, default: f64, min: f64, max: f64) -> Self {
+        self.setting(pattern, Setting::Number { default, min, max })
+    }
+
+    /// Boolean setting accepting `ON`, `OFF` and numbers
+    pub fn boolean(self, pattern: &str, default: bool) -> Self {
+        self.setting(pattern, Setting::Bool { default })
+    }
+
+    /// Restore defaults as on `*RST`
+    pub fn reset(&mut self) {
+        self.values.clear();
+    }
+
+    fn find(&self, tokens: &[String]) -> Option<(usize, Vec<u32>)> {
+        self.nodes.iter().enumerate()
+        .find_map(|(index, node)| node.pattern.matches(tokens).map(|suffixes| (index, suffixes)))
+    }
+
+    /// Handle single command of compound message
+    ///
+    /// `path` is the header path of previous command in the same message,
+    /// it is used for headers without leading `:` and updated on success.
+    pub fn handle(&mut self, cmd: &str, path: &mut Vec<String>) -> Result<Option<String>, ScpiError> {
+        let cmd = Command::parse(cmd, path)?;
+        let res = self.handle_command(&cmd).ok_or_else(undefined_header)?;
+        cmd.update_path(path);
+        res
+    }
+
+    /// Handle parsed command, `None` if no node matches its header
+    pub(crate) fn handle_command(&mut self, cmd: &Command) -> Option<Result<Option<String>, ScpiError>> {
+        let key = self.find(&cmd.tokens)?;
+        let setting = self.nodes[key.0].setting;
+        Some(if cmd.query {
+            match cmd.param.as_str() {
+                "" => Ok(Some(self.values.get(&key).copied().unwrap_or_else(|| setting.default_value()).format())),
+                param => setting.limit(param)
+                .map(|value| Some(value.format()))
+                .ok_or_else(|| error(-224, "Illegal parameter value")),
+            }
+        } else if cmd.param.is_empty() {
+            Err(error(-109, "Missing parameter"))
+        } else {
+            setting.parse(&cmd.param).map(|value| {
+                self.values.insert(key, value);
+                None
+            })
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(tree: &mut CommandTree, message: &str) -> Vec<Result<Option<String>, i32>> {
+        let mut path = Vec::new();
+        message.split(';')
+        .map(|cmd| tree.handle(cmd, &mut path).map_err(|e| e.code))
+        .collect()
+    }
+
+    #[test]
+    fn settings() {
+        let mut tree = CommandTree::new()
+        .number("[SOURce#]:VOLTage[:LEVel][:IMMediate]", 0.0, 0.0, 30.0)
+        .number("[SOURce#]:CURRent[:LEVel][:IMMediate]", 1.0, 0.0, 3.0)
+        .boolean("OUTPut#[:STATe]", false);
+
+        assert_eq!(handle(&mut tree, "VOLT 5"), vec![Ok(None)]);
+        assert_eq!(handle(&mut tree, "volt?"), vec![Ok(Some(String::from("+5.00000000E+00")))]);
+        assert_eq!(handle(&mut tree, "SOUR1:VOLTage:LEV:IMM?"), vec![Ok(Some(String::from("+5.00000000E+00")))]);
+        assert_eq!(handle(&mut tree, "SOUR2:VOLT?"), vec![Ok(Some(String::from("+0.00000000E+00")))]);
+        assert_eq!(
+            handle(&mut tree, "SOUR2:VOLT MAX;CURR 0.25;CURR?;:OUTP2:STAT ON;STAT?;:OUTP2?"),
+            vec![
+                Ok(None), Ok(None), Ok(Some(String::from("+2.50000000E-01"))),
+                Ok(None), Ok(Some(String::from("1"))), Ok(Some(String::from("1"))),
+            ],
+        );
+        assert_eq!(handle(&mut tree, "SOUR2:VOLT?;CURR? MAX"), vec![
+            Ok(Some(String::from("+3.00000000E+01"))), Ok(Some(String::from("+3.00000000E+00"))),
+        ]);
+        assert_eq!(handle(&mut tree, "VOLT 31;VOLT abc;VOLT;VOLT? FOO"), vec![Err(-222), Err(-104), Err(-109), Err(-224)]);
+        assert_eq!(handle(&mut tree, "VOLT nan;VOLT inf;OUTP NaN"), vec![Err(-104), Err(-104), Err(-104)]);
+        assert_eq!(handle(&mut tree, "VOLT?"), vec![Ok(Some(String::from("+5.00000000E+00")))]);
+        assert_eq!(format_number(f64::NAN), "+9.91000000E+37");
+        assert_eq!(format_number(f64::NEG_INFINITY), "-9.90000000E+37");
+        assert_eq!(handle(&mut tree, "VOLTA 1;LEV 1;OUTP:STAT2 1"), vec![Err(-113), Err(-113), Err(-113)]);
+
+        tree.reset();
+        assert_eq!(handle(&mut tree, "VOLT?;:OUTP?"), vec![
+            Ok(Some(String::from("+0.00000000E+00"))), Ok(Some(String::from("0"))),
+        ]);
+    }
+}
@@ -0,0 +1,200 @@
+use std::io::{self};
+use std::cmp::min;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+
+use crate::transport::rpc::{self, RpcCall, XdrReader, XdrWriter};
+use crate::transport::vxi11::*;
+use super::Instrument;
+
+
+/// Maximum size of `device_write` data, small enough to make clients split messages
+pub const MAX_RECV_SIZE: u32 = 64;
+
+/// Read reason: requested count reached
+const REASON_REQCNT: u32 = 1;
+
+/// Stand-in for portmapper and VXI-11 core channel server
+pub struct Vxi11Emulator {
+    portmap: TcpListener,
+    core: TcpListener,
+}
+
+impl Vxi11Emulator {
+    pub fn new(host: &str) -> io::Result<Self> {
+        Ok(Vxi11Emulator {
+            portmap: TcpListener::bind((host, 0))?,
+            core: TcpListener::bind((host, 0))?,
+        })
+    }
+
+    pub fn portmapper_address(&self) -> io::Result<SocketAddr> {
+        self.portmap.local_addr()
+    }
+
+    /// Serve single portmapper lookup and then single core channel connection
+    pub fn run(self) -> JoinHandle<io::Result<()>> {
+        thread::spawn(move || {
+            let core_port = self.core.local_addr()?.port();
+            let stream = self.portmap.incoming().next().unwrap()?;
+            serve(stream, |call| portmap(call, core_port))?;
+
+            let stream = self.core.incoming().next().unwrap()?;
+            let mut core = Core::default();
+            serve(stream, |call| core.call(call))
+        })
+    }
+}
+
+fn serve<F>(mut stream: TcpStream, mut handler: F) -> io::Result<()>
+where F: FnMut(&RpcCall) -> io::Result<Vec<u8>> {
+    loop {
+        match rpc::read_record(&mut stream)
+        .and_then(|record| RpcCall::parse(&record))
+        .and_then(|call| handler(&call))
+        .and_then(|reply| rpc::write_record(&mut stream, &reply)) {
+            Ok(()) => (),
+            Err(err) => match err.kind() {
+                io::ErrorKind::UnexpectedEof |
+                io::ErrorKind::ConnectionAborted |
+                io::ErrorKind::ConnectionReset |
+                io::ErrorKind::BrokenPipe => break Ok(()),
+                _ => break Err(err),
+            },
+        }
+    }
+}
+
+fn portmap(call: &RpcCall, core_port: u16) -> io::Result<Vec<u8>> {
+    if call.prog != rpc::PORTMAP_PROG || call.vers != rpc::PORTMAP_VERS {
+        return Ok(call.reply(rpc::PROG_UNAVAIL, &[]));
+    }
+    if call.procedure != rpc::PORTMAP_GETPORT {
+        return Ok(call.reply(rpc::PROC_UNAVAIL, &[]));
+    }
+    let mut r = XdrReader::new(&call.args);
+    let (prog, _vers, prot) = (r.u32()?, r.u32()?, r.u32()?);
+    let port = if prog == DEVICE_CORE && prot == rpc::IPPROTO_TCP { core_port } else { 0 };
+    let mut w = XdrWriter::new();
+    w.u32(port as u32);
+    Ok(call.reply(rpc::SUCCESS, &w.into_inner()))
+}
+
+/// Core channel state of single client
+#[derive(Default)]
+struct Core {
+    instrument: Instrument,
+    last_lid: u32,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// Address of client interrupt channel server
+    intr_addr: Option<SocketAddr>,
+    intr: Option<TcpStream>,
+    /// Handle of enabled service requests
+    srq_handle: Option<Vec<u8>>,
+    intr_xid: u32,
+    locked: bool,
+}
+
+impl Core {
+    fn call(&mut self, call: &RpcCall) -> io::Result<Vec<u8>> {
+        if call.prog != DEVICE_CORE || call.vers != DEVICE_CORE_VERSION {
+            return Ok(call.reply(rpc::PROG_UNAVAIL, &[]));
+        }
+        let mut r = XdrReader::new(&call.args);
+        let mut w = XdrWriter::new();
+        match call.procedure {
+            CREATE_LINK => {
+                let (_client_id, _lock, _lock_timeout) = (r.u32()?, r.u32()?, r.u32()?);
+                let _device = r.opaque()?;
+                self.last_lid += 1;
+                w.u32(0).u32(self.last_lid).u32(0).u32(MAX_RECV_SIZE);
+            },
+            DEVICE_WRITE => {
+                let (_lid, _io_timeout, _lock_timeout, flags) = (r.u32()?, r.u32()?, r.u32()?, r.u32()?);
+                let data = r.opaque()?;
+                self.input.extend_from_slice(data);
+                if flags & FLAG_END != 0 {
+                    self.process()?;
+                }
+                w.u32(0).u32(data.len() as u32);
+            },
+            DEVICE_READ => {
+                let (_lid, request_size) = (r.u32()?, r.u32()? as usize);
+                if self.output.is_empty() {
+                    w.u32(ERR_IO_TIMEOUT).u32(0).opaque(&[]);
+                } else {
+                    let num = min(request_size, self.output.len());
+                    let data = self.output.drain(..num).collect::<Vec<_>>();
+                    let reason = if self.output.is_empty() { REASON_END } else { REASON_REQCNT };
+                    w.u32(0).u32(reason).opaque(&data);
+                }
+            },
+            DEVICE_CLEAR => {
+                self.input.clear();
+                self.output.clear();
+                w.u32(0);
+            },
+            DEVICE_LOCK => {
+                self.locked = true;
+                w.u32(0);
+            },
+            DEVICE_UNLOCK => {
+                w.u32(if std::mem::take(&mut self.locked) { 0 } else { ERR_NO_LOCK_HELD });
+            },
+            DESTROY_LINK => {
+                w.u32(0);
+            },
+            CREATE_INTR_CHAN => {
+                let (addr, port, prog, vers, family) = (r.u32()?, r.u32()?, r.u32()?, r.u32()?, r.u32()?);
+                if prog == DEVICE_INTR && vers == DEVICE_INTR_VERSION && family == DEVICE_TCP {
+                    self.intr_addr = Some(SocketAddr::from((Ipv4Addr::from(addr), port as u16)));
+                    w.u32(0);
+                } else {
+                    w.u32(ERR_CHANNEL_NOT_ESTABLISHED);
+                }
+            },
+            DEVICE_ENABLE_SRQ => {
+                let (_lid, enable) = (r.u32()?, r.u32()? != 0);
+                self.srq_handle = if enable { Some(r.opaque()?.to_vec()) } else { None };
+                w.u32(0);
+            },
+            DESTROY_INTR_CHAN => {
+                self.intr_addr = None;
+                self.intr = None;
+                w.u32(0);
+            },
+            _ => return Ok(call.reply(rpc::PROC_UNAVAIL, &[])),
+        }
+        Ok(call.reply(rpc::SUCCESS, &w.into_inner()))
+    }
+
+    fn process(&mut self) -> io::Result<()> {
+        let input = std::mem::take(&mut self.input);
+        for line in input.split_inclusive(|&b| b == b'\n') {
+            if let Some(response) = self.instrument.handle(line) {
+                self.output.extend_from_slice(&response);
+            }
+        }
+        if self.instrument.take_service_request().is_some() {
+            self.send_srq()?;
+        }
+        Ok(())
+    }
+
+    /// Call `device_intr_srq` over interrupt channel without waiting for reply
+    fn send_srq(&mut self) -> io::Result<()> {
+        let (addr, handle) = match (self.intr_addr, &self.srq_handle) {
+            (Some(addr), Some(handle)) => (addr, handle),
+            _ => return Ok(()),
+        };
+        if self.intr.is_none() {
+            self.intr = Some(TcpStream::connect(addr)?);
+        }
+        self.intr_xid += 1;
+        let mut w = XdrWriter::new();
+        w.opaque(handle);
+        let msg = rpc::call_message(self.intr_xid, DEVICE_INTR, DEVICE_INTR_VERSION, DEVICE_INTR_SRQ, &w.into_inner());
+        rpc::write_record(self.intr.as_mut().unwrap(), &msg)
+    }
+}
@@ -0,0 +1,242 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::{KsLimitError, KsMessage, ResourceError};
+
+
+/// Error of communication with Keysight device
+#[derive(Debug)]
+pub enum KsError {
+    /// Underlying I/O error not covered by other variants
+    Io(io::Error),
+    /// Text response contains non-UTF-8 sequence
+    NonUtf8Text,
+    /// Byte after `#` in block header is not a digit
+    BadBlockHeader(u8),
+    /// Size field of block header is not a number
+    BlockSizeParse,
+    /// Something other than newline follows block content
+    TrailingGarbage { consumed: usize },
+    /// Read or write timed out
+    Timeout { consumed: usize },
+    /// Connection was closed by peer or not established
+    Disconnected { consumed: usize },
+    /// Response exceeds configured limits
+    Limit(KsLimitError),
+    /// Response data doesn't match the expected format
+    BadResponse(String),
+    /// Command can't be sent as is, e.g. it contains newline
+    BadCommand(String),
+    /// Resource string can't be parsed
+    Resource(ResourceError),
+    /// Operation is not supported by transport
+    Unsupported(&'static str),
+    /// Connection was lost and restored by reconnect policy, init sequence is replayed
+    ///
+    /// Operation that faced connection loss is not retried,
+    /// the command may or may not have been executed by instrument.
+    Reconnected { attempts: u32 },
+    /// Instrument reported an error in its error queue
+    InstrumentError { code: i32, message: String },
+    /// Instrument reported several errors at once, in order of queue
+    InstrumentErrors(Vec<ScpiError>),
+}
+
+/// Result of communication with Keysight device
+pub type KsResult<T> = Result<T, KsError>;
+
+impl KsError {
+    /// Number of response bytes consumed from the stream before failure
+    ///
+    /// When failure happens in the middle of response this is the amount of data
+    /// that was already read, including block header.
+    pub fn consumed(&self) -> Option<usize> {
+        match *self {
+            KsError::TrailingGarbage { consumed } |
+            KsError::Timeout { consumed } |
+            KsError::Disconnected { consumed } => Some(consumed),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn with_consumed(mut self, count: usize) -> Self {
+        match self {
+            KsError::TrailingGarbage { ref mut consumed } |
+            KsError::Timeout { ref mut consumed } |
+            KsError::Disconnected { ref mut consumed } => *consumed = count,
+            _ => (),
+        }
+        self
+    }
+
+    /// Error of single or several instrument errors, `None` if there are no errors
+    pub fn from_errors(mut errors: Vec<ScpiError>) -> Option<Self> {
+        match errors.len() {
+            0 => None,
+            1 => errors.pop().map(Self::from),
+            _ => Some(KsError::InstrumentErrors(errors)),
+        }
+    }
+
+    pub(crate) fn bad_response<S: Into<String>>(msg: S) -> Self {
+        KsError::BadResponse(msg.into())
+    }
+}
+
+impl fmt::Display for KsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KsError::Io(ref err) => write!(f, "I/O error: {}", err),
+            KsError::NonUtf8Text => write!(f, "text read: non-utf8 sequence found"),
+            KsError::BadBlockHeader(b) => {
+                write!(f, "bin read: byte 0x{:02x} after '#' is not digit", b)
+            },
+            KsError::BlockSizeParse => write!(f, "bin read: error parse message size"),
+            KsError::TrailingGarbage { consumed } => {
+                write!(f, "bin read: not only newline after message ({} bytes consumed)", consumed)
+            },
+            KsError::Timeout { consumed } => {
+                write!(f, "timed out ({} bytes consumed)", consumed)
+            },
+            KsError::Disconnected { consumed } => {
+                write!(f, "disconnected ({} bytes consumed)", consumed)
+            },
+            KsError::Limit(ref err) => write!(f, "{}", err),
+            KsError::BadResponse(ref msg) => write!(f, "bad response: {}", msg),
+            KsError::BadCommand(ref msg) => write!(f, "bad command: {}", msg),
+            KsError::Resource(ref err) => write!(f, "resource: {}", err),
+            KsError::Unsupported(what) => write!(f, "{} is not supported by transport", what),
+            KsError::Reconnected { attempts } => {
+                write!(f, "connection lost and restored after {} attempt(s)", attempts)
+            },
+            KsError::InstrumentError { code, ref message } => {
+                write!(f, "instrument error {}: {}", code, message)
+            },
+            KsError::InstrumentErrors(ref errors) => {
+                write!(f, "instrument errors:")?;
+                for err in errors {
+                    write!(f, " [{}]", err)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Error for KsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            KsError::Io(ref err) => Some(err),
+            KsError::Limit(ref err) => Some(err),
+            KsError::Resource(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KsError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut |
+            io::ErrorKind::WouldBlock => KsError::Timeout { consumed: 0 },
+            io::ErrorKind::UnexpectedEof |
+            io::ErrorKind::NotConnected |
+            io::ErrorKind::ConnectionReset |
+            io::ErrorKind::ConnectionAborted |
+            io::ErrorKind::BrokenPipe => KsError::Disconnected { consumed: 0 },
+            _ => KsError::Io(err),
+        }
+    }
+}
+
+impl From<ScpiError> for KsError {
+    fn from(err: ScpiError) -> Self {
+        KsError::InstrumentError { code: err.code, message: err.message }
+    }
+}
+
+impl From<KsLimitError> for KsError {
+    fn from(err: KsLimitError) -> Self {
+        KsError::Limit(err)
+    }
+}
+
+impl From<ResourceError> for KsError {
+    fn from(err: ResourceError) -> Self {
+        KsError::Resource(err)
+    }
+}
+
+impl From<KsError> for io::Error {
+    fn from(err: KsError) -> Self {
+        let kind = match err {
+            KsError::Io(err) => return err,
+            KsError::Timeout { .. } => io::ErrorKind::TimedOut,
+            KsError::Disconnected { .. } => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Entry of instrument error queue as returned by `SYSTem:ERRor?`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScpiError {
+    /// Error code, `0` means no error
+    pub code: i32,
+    pub message: String,
+}
+
+impl ScpiError {
+    /// Parse `<code>,"<message>"` response
+    pub fn parse(text: &str) -> KsResult<Self> {
+        let msg = KsMessage::parse(text.as_bytes())?;
+        let values = msg.values().collect::<Vec<_>>();
+        match values[..] {
+            [code, message] => code.as_i64()
+            .and_then(|code| i32::try_from(code).ok())
+            .zip(message.as_str_unquoted())
+            .map(|(code, message)| ScpiError { code, message: String::from(message) }),
+            _ => None,
+        }
+        .ok_or_else(|| KsError::bad_response(format!("error queue entry {:?}", text)))
+    }
+
+    /// Whether entry is an actual error, not `0,"No error"`
+    pub fn is_error(&self) -> bool {
+        self.code != 0
+    }
+}
+
+impl fmt::Display for ScpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},\"{}\"", self.code, self.message)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scpi_error() {
+        let e = ScpiError::parse("-113,\"Undefined header\"").unwrap();
+        assert_eq!(e, ScpiError { code: -113, message: String::from("Undefined header") });
+        assert!(e.is_error());
+        assert!(!ScpiError::parse("+0,\"No error\"").unwrap().is_error());
+        assert!(ScpiError::parse("Emulator").is_err());
+
+        match KsError::from_errors(vec![e.clone()]) {
+            Some(KsError::InstrumentError { code: -113, .. }) => (),
+            other => panic!("{:?}", other),
+        }
+        match KsError::from_errors(vec![e.clone(), e]) {
+            Some(KsError::InstrumentErrors(errors)) => assert_eq!(errors.len(), 2),
+            other => panic!("{:?}", other),
+        }
+        assert!(KsError::from_errors(Vec::new()).is_none());
+    }
+}
@@ -1,8 +1,37 @@
 use std::io::prelude::*;
-use std::io::{self, BufReader};
-use std::net::{TcpStream};
+use std::io::{self};
 
-use lxi::{LxiHook, LxiDevice};
+mod value;
+mod block;
+mod device;
+mod limits;
+mod error;
+mod transport;
+mod resource;
+mod decoder;
+mod reconnect;
+mod complete;
+mod status;
+mod srq;
+mod lock;
+#[cfg(feature = "tokio")]
+mod async_device;
+
+pub use value::{KsValue, KsMessage};
+pub use block::{ByteOrder, BlockElement, decode_block};
+pub use device::{KsDevice, DEFAULT_TERMINATOR, DEFAULT_DRAIN_TIMEOUT};
+pub use limits::{KsLimits, KsLimitError, DEFAULT_MAX_BLOCK_SIZE, DEFAULT_MAX_LINE_LENGTH};
+pub use error::{KsError, KsResult, ScpiError};
+pub use decoder::{KsDecoder};
+pub use reconnect::{ReconnectPolicy};
+pub use complete::{Completion, DEFAULT_POLL_INTERVAL};
+pub use status::{StatusRegister, StatusByte, EventStatus, OperationStatus, QuestionableStatus};
+pub use srq::{SrqDispatcher};
+pub use lock::{KsLockGuard};
+#[cfg(feature = "tokio")]
+pub use async_device::{KsAsyncDevice, AsyncTransport};
+pub use transport::{Transport, TcpTransport, TcpOptions, DEFAULT_CONTROL_TIMEOUT, Vxi11Transport, Vxi11Options, HislipTransport, HislipOptions};
+pub use resource::{ResourceName, ResourceError, KsDynDevice, DEFAULT_VXI11_DEVICE, open, open_timeout};
 
 
 fn remove_newline(text: &mut Vec<u8>) {
@@ -48,85 +77,186 @@ impl KsData {
             None
         }
     }
+    /// Parse text response as single IEEE 488.2 response data element
+    pub fn to_value(&self) -> KsResult<KsValue> {
+        match *self {
+            KsData::Text(ref text) => KsValue::parse(text),
+            KsData::Bin(_) => Err(KsError::bad_response("value parse: binary response")),
+        }
+    }
+    /// Decode binary response as array of numbers of given type and byte order
+    pub fn decode_block<T: BlockElement>(&self, order: ByteOrder) -> KsResult<Vec<T>> {
+        match *self {
+            KsData::Bin(ref data) => decode_block(data, order),
+            KsData::Text(_) => Err(KsError::bad_response("block decode: text response")),
+        }
+    }
+    /// Parse response as compound message, binary response becomes single block element
+    pub fn to_message(&self) -> KsResult<KsMessage> {
+        match *self {
+            KsData::Text(ref text) => KsMessage::parse(text.as_bytes()),
+            KsData::Bin(ref data) => Ok(KsMessage::from(KsValue::Block(data.clone()))),
+        }
+    }
 }
 
-impl LxiHook for KsHook {
-    type Output = KsData;
-    fn read(stream: &mut BufReader<TcpStream>) -> io::Result<Self::Output> {
-        let mut buf = vec![0];
-        stream.read_exact(&mut buf)
+/// Reader that counts consumed bytes to report them on failure
+struct Counted<'a, R> {
+    inner: &'a mut R,
+    count: usize,
+}
+
+impl<'a, R: BufRead> Read for Counted<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let num = self.inner.read(buf)?;
+        self.count += num;
+        Ok(num)
+    }
+}
+
+impl<'a, R: BufRead> BufRead for Counted<'a, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        self.count += amt;
+        self.inner.consume(amt)
+    }
+}
+
+impl KsHook {
+    /// Read single response from buffered stream
+    pub fn read_from<R: BufRead>(stream: &mut R, limits: &KsLimits) -> KsResult<KsData> {
+        Self::read_with_end(stream, limits, |_| None)
+    }
+
+    /// Same as [`read_from`](Self::read_from) with END indication,
+    /// `end` tells whether data buffered in `stream` ends with END, see [`Transport::read_end`]
+    pub(crate) fn read_with_end<R, E>(stream: &mut R, limits: &KsLimits, end: E) -> KsResult<KsData>
+    where R: BufRead, E: Fn(&R) -> Option<bool> {
+        let mut decoder = KsDecoder::new(*limits);
+        loop {
+            let buf = match stream.fill_buf() {
+                Ok(buf) => buf,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(KsError::from(e).with_consumed(decoder.consumed())),
+            };
+            if buf.is_empty() {
+                return decoder.finish();
+            }
+            let end = end(stream);
+            // Buffer is filled already, so no I/O is done here
+            let buf = match stream.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) => return Err(KsError::from(e).with_consumed(decoder.consumed())),
+            };
+            let (num, res) = decoder.decode_with_end(buf, end);
+            stream.consume(num);
+            if let Some(res) = res {
+                return res;
+            }
+        }
+    }
+
+    /// Read binary block response copying its content into `writer` chunk by chunk
+    ///
+    /// Memory usage is bounded by the stream buffer size regardless of the declared block size,
+    /// so block size limit is not applied here.
+    /// `progress` is called after each chunk with the number of bytes copied so far
+    /// and the total block size if it is known from the header.
+    pub fn read_block_into<R, W, P>(stream: &mut R, writer: &mut W, progress: P) -> KsResult<usize>
+    where R: BufRead, W: Write, P: FnMut(usize, Option<usize>) {
+        Self::read_block_with_end(stream, writer, progress, |_| None)
+    }
+
+    /// Same as [`read_block_into`](Self::read_block_into) with END indication like in `read_with_end`
+    pub(crate) fn read_block_with_end<R, W, P, E>(stream: &mut R, writer: &mut W, progress: P, end: E) -> KsResult<usize>
+    where R: BufRead, W: Write, P: FnMut(usize, Option<usize>), E: Fn(&R) -> Option<bool> {
+        let mut stream = Counted { inner: stream, count: 0 };
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).map_err(KsError::from)
         .and_then(|()| {
             if buf[0] != b'#' {
-                // Ascii format
-                stream.read_until(b'\n', &mut buf)
-                .map(move |_num| { remove_newline(&mut buf); buf })
-                .and_then(|buf| String::from_utf8(buf).map_err(|_| {
-                    io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "text read: non-utf8 sequence found",
-                    )
-                })).map(|text| KsData::from_text(text))
-            } else {
-                // Binary format
-                stream.read_exact(&mut buf)
-                .and_then(|()| {
-                    (buf[0] as char).to_digit(10)
-                    .ok_or(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "bin read: second byte is not digit",
-                    ))
-                })
-                .and_then(|n| {
-                    buf.resize(n as usize, b'\0');
-                    stream.read_exact(&mut buf)
-                })
-                .and_then(|()| {
-                    String::from_utf8_lossy(&buf).parse::<usize>()
-                    .map_err(|_e| io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "bin read: error parse message size",
-                    ))
-                })
-                .and_then(|n| {
-                    buf.resize(n, b'\0');
-                    stream.read_exact(&mut buf)
-                })
-                .and_then(|()| {
-                    let mut end = Vec::new();
-                    stream.read_until(b'\n', &mut end)
-                    .map(|_k| end)
-                })
-                .and_then(|mut end| {
-                    remove_newline(&mut end);
-                    if end.len() > 0 {
-                        Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "bin read: not only newline after message",
-                        ))
-                    } else {
-                        Ok(KsData::from_bin(buf))
-                    }
-                })
+                return Err(KsError::bad_response("response is not a block"));
             }
+            Self::block_digits(buf[1])
         })
+        .and_then(|n| Self::copy_block(&mut stream, n, writer, progress, |s: &Counted<R>| end(s.inner)))
+        .map_err(|e| e.with_consumed(stream.count))
     }
-}
 
-/// Abstract Keysignt LXI device
-pub type KsDevice = LxiDevice<KsHook>;
+    /// Number of block size digits from the byte following `#`
+    fn block_digits(b: u8) -> KsResult<usize> {
+        (b as char).to_digit(10)
+        .map(|n| n as usize)
+        .ok_or(KsError::BadBlockHeader(b))
+    }
+
+    /// Copy block content after `#<n>` header, `#0` means indefinite block ending with newline,
+    /// or with newline followed by END if `end` knows it
+    fn copy_block<R, W, P, E>(stream: &mut R, n: usize, writer: &mut W, mut progress: P, end: E) -> KsResult<usize>
+    where R: BufRead, W: Write, P: FnMut(usize, Option<usize>), E: Fn(&R) -> Option<bool> {
+        let size = if n == 0 {
+            None
+        } else {
+            let mut buf = vec![0; n];
+            stream.read_exact(&mut buf)?;
+            Some(String::from_utf8_lossy(&buf).parse::<usize>()
+            .map_err(|_e| KsError::BlockSizeParse)?)
+        };
+
+        let mut done = 0;
+        loop {
+            if size == Some(done) {
+                break;
+            }
+            if stream.fill_buf()?.is_empty() {
+                return Err(KsError::Disconnected { consumed: 0 });
+            }
+            let end = end(stream);
+            let buf = stream.fill_buf()?;
+            let (len, last) = match (size, end) {
+                (Some(size), _) => (buf.len().min(size - done), false),
+                (None, Some(true)) if buf.ends_with(b"\n") => (buf.len() - 1, true),
+                (None, Some(end)) => (buf.len(), end),
+                (None, None) => match buf.iter().position(|&b| b == b'\n') {
+                    Some(pos) => (pos, true),
+                    None => (buf.len(), false),
+                },
+            };
+            let skip = (last && buf.len() > len) as usize;
+            writer.write_all(&buf[..len])?;
+            stream.consume(len + skip);
+            done += len;
+            progress(done, size);
+            if last {
+                return Ok(done);
+            }
+        }
 
+        let mut end = Vec::new();
+        stream.read_until(b'\n', &mut end)?;
+        remove_newline(&mut end);
+        if !end.is_empty() {
+            Err(KsError::TrailingGarbage { consumed: 0 })
+        } else {
+            Ok(done)
+        }
+    }
+}
 
-#[cfg(test)]
-mod emul;
+#[cfg(any(test, feature = "emulator"))]
+pub mod emul;
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::sync::{mpsc, Arc, Mutex};
     use std::thread;
     use std::time::{Duration};
 
-    use emul::{Emulator};
+    use emul::{Emulator, EmulatorHandle, Vxi11Emulator, HislipEmulator, Script, Rule, Reply, CommandTree, Fault, DUMP_SIZE};
 
     #[test]
     fn emulate() {
@@ -152,4 +282,895 @@ mod tests {
 
         e.join().unwrap().unwrap();
     }
+
+    #[test]
+    fn emulate_indefinite_block() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let p = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let mut d = KsDevice::new((String::from("localhost"), p), None);
+            d.connect().unwrap();
+
+            d.send(b"DATA0?").unwrap();
+            assert_eq!(d.receive().unwrap(), KsData::from_bin(vec![0, 255, 13, 128]));
+
+            d.send(b"*IDN?").unwrap();
+            assert_eq!(d.receive().unwrap(), KsData::from_text(String::from("Emulator")));
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_block_streaming() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let p = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let mut d = KsDevice::new((String::from("localhost"), p), None);
+            d.connect().unwrap();
+
+            let mut data = Vec::new();
+            let mut chunks = 0;
+            let mut last = (0, None);
+            d.send(b"DUMP?").unwrap();
+            let n = d.receive_block_into(&mut data, |done, total| {
+                chunks += 1;
+                last = (done, total);
+            }).unwrap();
+            assert_eq!(n, DUMP_SIZE);
+            assert!(chunks > 1);
+            assert_eq!(last, (DUMP_SIZE, Some(DUMP_SIZE)));
+            assert!(data.iter().enumerate().all(|(i, &b)| b == i as u8));
+
+            d.send(b"DATA0?").unwrap();
+            let mut data = Vec::new();
+            d.receive_block_into(&mut data, |_, total| assert_eq!(total, None)).unwrap();
+            assert_eq!(data, vec![0, 255, 13, 128]);
+
+            d.send(b"*IDN?").unwrap();
+            assert!(d.receive_block_into(&mut Vec::new(), |_, _| ()).is_err());
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_query() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let p = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let mut d = KsDevice::new((String::from("localhost"), p), None);
+            d.connect().unwrap();
+
+            assert_eq!(d.query("*IDN?").unwrap(), KsData::from_text(String::from("Emulator")));
+            assert_eq!(d.query_str("*IDN?").unwrap(), "Emulator");
+            assert_eq!(d.query_f64("MEAS?").unwrap(), 1.5);
+            assert!(d.query_bool("OUTP?").unwrap());
+            assert_eq!(d.query_i64("OUTP?").unwrap(), 1);
+            assert_eq!(d.query_block("DATA?").unwrap(), vec![0, 255, 10, 128]);
+            match d.query_f64("*IDN?") {
+                Err(KsError::BadResponse(_)) => (),
+                other => panic!("{:?}", other),
+            }
+            match d.write("*RST\n*IDN?") {
+                Err(KsError::BadCommand(_)) => (),
+                other => panic!("{:?}", other),
+            }
+            assert_eq!(d.query_message("MEAS?").unwrap()[0], [KsValue::Decimal(1.5)]);
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_error_check() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let p = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let mut d = KsDevice::new((String::from("localhost"), p), None);
+            d.connect().unwrap();
+
+            d.write("FOO").unwrap();
+            d.write("BAR").unwrap();
+            assert_eq!(d.read_errors().unwrap().len(), 2);
+            d.check_errors().unwrap();
+
+            d.set_error_check(true);
+            d.write("*RST").unwrap();
+            assert_eq!(d.query_f64("MEAS?").unwrap(), 1.5);
+            match d.write("FOO") {
+                Err(KsError::InstrumentError { code: -113, message }) => {
+                    assert_eq!(message, "Undefined header");
+                },
+                other => panic!("{:?}", other),
+            }
+
+            match d.batch(|d| {
+                d.write("FOO")?;
+                d.write("BAR")?;
+                d.query_str("*IDN?")
+            }) {
+                Err(KsError::InstrumentErrors(errors)) => assert_eq!(errors.len(), 2),
+                other => panic!("{:?}", other),
+            }
+            assert_eq!(d.batch(|d| d.query_str("*IDN?")).unwrap(), "Emulator");
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_tcp_options() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let p = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let options = TcpOptions {
+                nodelay: true,
+                keepalive: Some(Duration::from_secs(10)),
+                recv_buffer_size: Some(1 << 16),
+                send_buffer_size: Some(1 << 16),
+                ..TcpOptions::with_timeout(Some(Duration::from_secs(1)))
+            };
+            let t = TcpTransport::new((String::from("localhost"), p), options);
+            let mut d = KsDevice::with_transport(t);
+            assert_eq!(d.timeout(), Some(Duration::from_secs(1)));
+            d.set_terminator(b"\n");
+            d.connect().unwrap();
+            assert!(d.is_connected());
+
+            d.send(b"*IDN?").unwrap();
+            assert_eq!(d.receive().unwrap(), KsData::from_text(String::from("Emulator")));
+
+            d.disconnect().unwrap();
+            assert!(!d.is_connected());
+            match d.send(b"*IDN?") {
+                Err(KsError::Disconnected { .. }) => (),
+                other => panic!("{:?}", other),
+            }
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_vxi11() {
+        let e = Vxi11Emulator::new("localhost").unwrap();
+        let pm = e.portmapper_address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let options = Vxi11Options { portmapper_port: pm, ..Vxi11Options::default() };
+            let t = Vxi11Transport::new(String::from("localhost"), String::from("inst0"), options);
+            let mut d = KsDevice::with_transport(t);
+            d.connect().unwrap();
+
+            assert_eq!(d.query_str("*IDN?").unwrap(), "Emulator");
+            assert_eq!(d.query_str(&format!("*IDN?{:100}", "")).unwrap(), "Emulator");
+            assert_eq!(d.query_block("DATA?").unwrap(), vec![0, 255, 10, 128]);
+            assert_eq!(d.query_block("DATA0?").unwrap(), vec![0, 255, 13, 128]);
+            assert_eq!(d.query_block("DATA0:NL?").unwrap(), vec![1, b'\n', 2]);
+            let mut data = Vec::new();
+            d.send(b"DATA0:NL?").unwrap();
+            d.receive_block_into(&mut data, |_, _| ()).unwrap();
+            assert_eq!(data, vec![1, b'\n', 2]);
+            assert_eq!(d.query_f64("MEAS?").unwrap(), 1.5);
+
+            let mut data = Vec::new();
+            d.send(b"DUMP?").unwrap();
+            assert_eq!(d.receive_block_into(&mut data, |_, _| ()).unwrap(), DUMP_SIZE);
+
+            {
+                let mut guard = d.lock(Duration::from_secs(1)).unwrap();
+                assert_eq!(guard.query_str("*IDN?").unwrap(), "Emulator");
+            }
+            assert!(d.unlock().is_err());
+            match d.lock_shared("station", Duration::from_secs(1)) {
+                Err(KsError::Unsupported(_)) => (),
+                other => panic!("{:?}", other.map(|_| ())),
+            }
+
+            let (tx, rx) = mpsc::channel();
+            d.on_service_request(move |stb| { let _ = tx.send(stb); }).unwrap();
+            d.set_service_request_enable(StatusByte::ESB).unwrap();
+            d.set_event_status_enable(EventStatus::OPC).unwrap();
+            d.write("*OPC").unwrap();
+            assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), StatusByte::MSS);
+
+            d.write("FOO").unwrap();
+            match d.check_errors() {
+                Err(KsError::InstrumentError { code: -113, .. }) => (),
+                other => panic!("{:?}", other),
+            }
+            match d.receive() {
+                Err(KsError::Timeout { .. }) => (),
+                other => panic!("{:?}", other),
+            }
+
+            d.disconnect().unwrap();
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_open() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let port = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let mut d = open(&format!("TCPIP::localhost::{}::SOCKET", port)).unwrap();
+            assert!(d.is_connected());
+            assert_eq!(d.query_str("*IDN?").unwrap(), "Emulator");
+            d.disconnect().unwrap();
+        }
+
+        e.join().unwrap().unwrap();
+
+        match open("ASRL1::INSTR") {
+            Err(KsError::Resource(ResourceError::UnsupportedInterface(_))) => (),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn emulate_async() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let port = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let mut d = KsAsyncDevice::connect(("localhost", port), Some(Duration::from_secs(1))).await.unwrap();
+
+            assert_eq!(d.query_str("*IDN?").await.unwrap(), "Emulator");
+            assert_eq!(d.query_block("DATA?").await.unwrap(), vec![0, 255, 10, 128]);
+            assert_eq!(d.query_block("DATA0?").await.unwrap(), vec![0, 255, 13, 128]);
+            assert_eq!(d.query_f64("MEAS?").await.unwrap(), 1.5);
+
+            // Interrupted response is continued by the next receive
+            d.send(b"DUMP?").await.unwrap();
+            let data = match d.receive_timeout(Some(Duration::from_nanos(1))).await {
+                Err(KsError::Timeout { .. }) => d.receive().await.unwrap(),
+                res => res.unwrap(),
+            };
+            assert_eq!(data.into_bin().unwrap().len(), DUMP_SIZE);
+            assert_eq!(d.query_str("*IDN?").await.unwrap(), "Emulator");
+
+            d.set_error_check(true);
+            match d.write("FOO").await {
+                Err(KsError::InstrumentError { code: -113, .. }) => (),
+                other => panic!("{:?}", other),
+            }
+        });
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn emulate_async_resync() {
+        let e = Emulator::new(("localhost", 0)).unwrap().with_fault(Fault::Delay(Duration::from_millis(100)));
+        let port = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let mut d = KsAsyncDevice::connect(("localhost", port), Some(Duration::from_secs(1))).await.unwrap();
+
+            d.send(b"MEAS?").await.unwrap();
+            match d.receive_timeout(Some(Duration::from_millis(20))).await {
+                Err(KsError::Timeout { consumed: 0 }) => (),
+                other => panic!("{:?}", other),
+            }
+            assert!(d.response_pending());
+
+            // Late response of `MEAS?` is discarded
+            assert_eq!(d.query_str("*IDN?").await.unwrap(), "Emulator");
+            assert!(!d.response_pending());
+        });
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_reconnect() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let port = e.address().unwrap().port();
+        let e = e.run_sessions(2);
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let mut d = KsDevice::new((String::from("localhost"), port), Some(Duration::from_secs(1)));
+            d.set_reconnect_policy(Some(ReconnectPolicy {
+                initial_delay: Duration::from_millis(10),
+                ..ReconnectPolicy::default()
+            }));
+            d.set_init_sequence(vec!["*ESE 1"]);
+            d.connect().unwrap();
+            assert_eq!(d.query_i64("*ESE?").unwrap(), 1);
+            d.write("*ESE 0").unwrap();
+
+            d.write("DROP").unwrap();
+            match d.query("*IDN?") {
+                Err(KsError::Reconnected { attempts: 1 }) => (),
+                other => panic!("{:?}", other),
+            }
+            assert_eq!(d.query_i64("*ESE?").unwrap(), 1);
+            d.disconnect().unwrap();
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_clear() {
+        let e = Emulator::new(("localhost", 0)).unwrap()
+        .with_control(("localhost", 0)).unwrap();
+        let port = e.address().unwrap().port();
+        let control_port = e.control_address().unwrap().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let options = TcpOptions {
+                control_port: Some(control_port),
+                ..TcpOptions::with_timeout(Some(Duration::from_secs(1)))
+            };
+            let mut d = KsDevice::with_transport(TcpTransport::new((String::from("localhost"), port), options));
+            d.connect().unwrap();
+
+            d.send(b"DUMP?").unwrap();
+            assert!(d.response_pending());
+            assert_eq!(d.query_str("*IDN?").unwrap(), "Emulator");
+            assert!(!d.response_pending());
+
+            d.send(b"DUMP?").unwrap();
+            d.clear().unwrap();
+            assert!(!d.response_pending());
+            d.send(b"*IDN?").unwrap();
+            assert_eq!(d.receive().unwrap(), KsData::from_text(String::from("Emulator")));
+
+            d.send(b"*ESE 1").unwrap();
+            assert!(!d.response_pending());
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_complete() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let port = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let mut d = KsDevice::new((String::from("localhost"), port), Some(Duration::from_secs(1)));
+            d.connect().unwrap();
+
+            d.wait_complete(Duration::from_secs(5)).unwrap();
+            assert_eq!(d.timeout(), Some(Duration::from_secs(1)));
+
+            d.set_completion(Completion::Poll { interval: DEFAULT_POLL_INTERVAL });
+            d.wait_complete(Duration::from_secs(5)).unwrap();
+            assert_eq!(d.query_i64("*ESR?").unwrap(), 0);
+
+            // Stale response is discarded before `*OPC` is sent
+            d.send(b"MEAS?").unwrap();
+            d.wait_complete(Duration::from_secs(5)).unwrap();
+            assert!(!d.response_pending());
+            assert_eq!(d.query_i64("*ESR?").unwrap(), 0);
+
+            d.set_completion(Completion::ServiceRequest);
+            match d.wait_complete(Duration::from_secs(1)) {
+                Err(KsError::Unsupported(_)) => (),
+                other => panic!("{:?}", other),
+            }
+            assert_eq!(d.query_i64("*ESE?").unwrap(), 0);
+            assert_eq!(d.query_i64("*SRE?").unwrap(), 0);
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_status() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let port = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let mut d = KsDevice::new((String::from("localhost"), port), Some(Duration::from_secs(1)));
+            d.connect().unwrap();
+
+            assert_eq!(d.status_byte().unwrap(), StatusByte::empty());
+            d.write("FOO").unwrap();
+            assert_eq!(d.status_byte().unwrap(), StatusByte::ERROR_QUEUE);
+            d.clear_status().unwrap();
+
+            d.set_event_status_enable(EventStatus::OPC | EventStatus::CME).unwrap();
+            assert_eq!(d.event_status_enable().unwrap(), EventStatus::OPC | EventStatus::CME);
+            d.set_service_request_enable(StatusByte::ESB).unwrap();
+            d.write("*OPC").unwrap();
+            let stb = d.status_byte().unwrap();
+            assert_eq!(stb, StatusByte::ESB | StatusByte::MSS);
+            assert_eq!(stb.explain(), vec!["standard event status summary", "service requested"]);
+            assert_eq!(d.event_status().unwrap(), EventStatus::OPC);
+            assert_eq!(d.event_status().unwrap(), EventStatus::empty());
+
+            let mask = OperationStatus::MEASURING | OperationStatus::WAITING_TRIGGER;
+            d.set_operation_enable(mask).unwrap();
+            assert_eq!(d.operation_enable().unwrap(), mask);
+            assert_eq!(d.operation_condition().unwrap(), OperationStatus::empty());
+            assert_eq!(d.operation_event().unwrap(), OperationStatus::empty());
+            d.set_questionable_enable(QuestionableStatus::VOLTAGE).unwrap();
+            assert_eq!(d.questionable_enable().unwrap(), QuestionableStatus::VOLTAGE);
+            assert_eq!(d.questionable_condition().unwrap(), QuestionableStatus::empty());
+            assert_eq!(d.questionable_event().unwrap(), QuestionableStatus::empty());
+            d.preset_status().unwrap();
+            assert_eq!(d.operation_enable().unwrap(), OperationStatus::empty());
+
+            assert!(d.read_errors().unwrap().is_empty());
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_srq_poll() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let port = e.address().unwrap().port();
+        // Status byte poller of transport uses the second session
+        let e = e.run_sessions(2);
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let mut d = KsDevice::new((String::from("localhost"), port), Some(Duration::from_secs(1)));
+            d.connect().unwrap();
+
+            d.set_service_request_enable(StatusByte::ESB).unwrap();
+            d.set_event_status_enable(EventStatus::OPC).unwrap();
+            assert_eq!(d.poll_service_request().unwrap(), None);
+            d.write("*OPC").unwrap();
+            assert_eq!(d.poll_service_request().unwrap(), Some(StatusByte::ESB | StatusByte::MSS));
+            assert_eq!(d.poll_service_request().unwrap(), None);
+            d.clear_status().unwrap();
+
+            let rx = d.subscribe_service_requests().unwrap();
+            let (tx, calls) = mpsc::channel();
+            d.on_service_request(move |stb| tx.send(stb).unwrap()).unwrap();
+            d.write("*OPC").unwrap();
+            assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), StatusByte::ESB | StatusByte::MSS);
+            assert_eq!(calls.recv_timeout(Duration::from_secs(1)).unwrap(), StatusByte::ESB | StatusByte::MSS);
+            assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_lock() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let port = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let mut d = KsDevice::new((String::from("localhost"), port), Some(Duration::from_secs(1)));
+            d.connect().unwrap();
+            d.set_error_check(true);
+
+            {
+                let mut guard = d.lock(Duration::from_secs(1)).unwrap();
+                guard.write("*CLS").unwrap();
+            }
+            match d.unlock() {
+                Err(KsError::InstrumentError { code: -221, .. }) => (),
+                other => panic!("{:?}", other),
+            }
+            d.lock(Duration::from_secs(1)).unwrap().unlock().unwrap();
+            assert!(d.lock_shared("station", Duration::from_secs(1)).is_err());
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_sessions() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let port = e.address().unwrap().port();
+        let e = e.run_sessions(2);
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let mut a = KsDevice::new((String::from("localhost"), port), Some(Duration::from_secs(1)));
+            let mut b = KsDevice::new((String::from("localhost"), port), Some(Duration::from_secs(1)));
+            a.connect().unwrap();
+            b.connect().unwrap();
+
+            a.set_event_status_enable(EventStatus::CME).unwrap();
+            assert_eq!(b.event_status_enable().unwrap(), EventStatus::CME);
+
+            let guard = a.lock(Duration::from_secs(1)).unwrap();
+            match b.lock(Duration::from_millis(200)) {
+                Err(KsError::Timeout { .. }) => (),
+                Err(err) => panic!("{:?}", err),
+                Ok(_) => panic!("lock is granted to both sessions"),
+            }
+            drop(guard);
+            b.lock(Duration::from_secs(1)).unwrap().unlock().unwrap();
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_stop() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let port = e.address().unwrap().port();
+        let e = e.run_forever();
+
+        let mut d = KsDevice::new((String::from("localhost"), port), Some(Duration::from_secs(1)));
+        for _ in 0..2 {
+            d.connect().unwrap();
+            assert_eq!(d.query_str("*IDN?").unwrap(), "Emulator");
+            d.write("*CLS").unwrap();
+            d.disconnect().unwrap();
+        }
+        d.connect().unwrap();
+        d.send(b"*RST").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        assert!(!e.is_finished());
+        e.stop();
+        assert!(e.log().contains("*RST"));
+        assert_eq!(e.log().commands(), vec!["*IDN?", "*CLS", "*IDN?", "*CLS", "*RST"]);
+        e.join().unwrap().unwrap();
+        match d.receive() {
+            Err(KsError::Disconnected { .. }) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn emulate_script() {
+        let volt = Arc::new(Mutex::new(String::from("+0.0")));
+        let script = Script::new()
+        .rule(Rule::exact("*IDN?").text("Acme,DMM,1,1.0"))
+        .rule(Rule::exact("TRAC:DATA?").block(&[1, 2, 3]).text("#0\x04\x05"))
+        .rule(Rule::regex("(?i)^VOLT$").unwrap().func({
+            let volt = volt.clone();
+            move |cmd| {
+                *volt.lock().unwrap() = String::from(cmd.split_whitespace().nth(1).unwrap_or("+0.0"));
+                Reply::None
+            }
+        }))
+        .rule(Rule::regex("(?i)^VOLT\\?$").unwrap().func(move |_| Reply::Text(volt.lock().unwrap().clone())))
+        .rule(Rule::exact("MEAS:VOLT?").text("+1.0").text("+2.0"));
+        let e = Emulator::new(("localhost", 0)).unwrap().with_script(script);
+        let port = e.address().unwrap().port();
+        let e = e.run();
+
+        {
+            let mut d = KsDevice::new((String::from("localhost"), port), Some(Duration::from_secs(1)));
+            d.connect().unwrap();
+            d.set_error_check(true);
+
+            assert_eq!(d.query("*IDN?").unwrap(), KsData::from_text(String::from("Acme,DMM,1,1.0")));
+            assert_eq!(d.query_block("TRAC:DATA?").unwrap(), vec![1, 2, 3]);
+            assert_eq!(d.query_block("TRAC:DATA?").unwrap(), vec![4, 5]);
+            d.write("volt 5").unwrap();
+            assert_eq!(d.query_f64("VOLT?").unwrap(), 5.0);
+            assert_eq!(d.query_f64("MEAS:VOLT?").unwrap(), 1.0);
+            assert_eq!(d.query_f64("MEAS:VOLT?").unwrap(), 2.0);
+            assert_eq!(d.query_f64("MEAS:VOLT?").unwrap(), 2.0);
+            assert_eq!(d.query_f64("MEAS?").unwrap(), 1.5);
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_tree() {
+        let tree = CommandTree::new()
+        .number("[SOURce#]:VOLTage[:LEVel][:IMMediate]", 0.0, 0.0, 30.0)
+        .number("[SOURce#]:CURRent[:LEVel][:IMMediate]", 1.0, 0.0, 3.0)
+        .boolean("OUTPut#[:STATe]", false);
+        let e = Emulator::new(("localhost", 0)).unwrap().with_tree(tree);
+        let port = e.address().unwrap().port();
+        let e = e.run();
+
+        {
+            let mut d = KsDevice::new((String::from("localhost"), port), Some(Duration::from_secs(1)));
+            d.connect().unwrap();
+            d.set_error_check(true);
+
+            d.write("VOLT 5").unwrap();
+            assert_eq!(d.query("VOLT?").unwrap(), KsData::from_text(String::from("+5.00000000E+00")));
+            assert_eq!(d.query_f64("SOURce1:VOLTage:LEVel?").unwrap(), 5.0);
+            let m = d.query_message("SOUR2:CURR MAX;VOLT?;CURR?;:OUTP2 ON;*ESE?;:OUTP2:STAT?").unwrap();
+            assert_eq!(m.values().map(|v| v.as_f64().unwrap()).collect::<Vec<_>>(), vec![0.0, 3.0, 0.0, 1.0]);
+            match d.write("SOUR:VOLTS 1") {
+                Err(KsError::InstrumentError { code: -113, .. }) => (),
+                other => panic!("{:?}", other),
+            }
+            match d.write("VOLT 100") {
+                Err(KsError::InstrumentError { code: -222, .. }) => (),
+                other => panic!("{:?}", other),
+            }
+            d.write("*RST").unwrap();
+            assert_eq!(d.query_f64("VOLT?").unwrap(), 0.0);
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    fn faulty(fault: Fault, timeout: Duration) -> (EmulatorHandle, KsDevice) {
+        let e = Emulator::new(("localhost", 0)).unwrap().with_fault(fault);
+        let port = e.address().unwrap().port();
+        let e = e.run();
+        let mut d = KsDevice::new((String::from("localhost"), port), Some(timeout));
+        d.connect().unwrap();
+        (e, d)
+    }
+
+    #[test]
+    fn emulate_faults() {
+        let (e, mut d) = faulty(Fault::Delay(Duration::from_millis(300)), Duration::from_millis(100));
+        match d.query("*IDN?") {
+            Err(KsError::Timeout { consumed: 0 }) => (),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(d.receive_timeout(Some(Duration::from_secs(1))).unwrap(), KsData::from_text(String::from("Emulator")));
+        drop(d);
+        e.join().unwrap().unwrap();
+
+        let stall = Fault::Stall { after: 10, duration: Duration::from_millis(300) };
+        let (e, mut d) = faulty(stall, Duration::from_millis(100));
+        match d.query_block("DUMP?") {
+            Err(KsError::Timeout { consumed: 10 }) => (),
+            other => panic!("{:?}", other),
+        }
+        drop(d);
+        e.join().unwrap().unwrap();
+
+        let (e, mut d) = faulty(Fault::BadBlockHeader, Duration::from_secs(1));
+        match d.query_block("DATA?") {
+            Err(KsError::BadBlockHeader(b'x')) => (),
+            other => panic!("{:?}", other),
+        }
+        drop(d);
+        e.join().unwrap().unwrap();
+
+        let (e, mut d) = faulty(Fault::BadBlockSize, Duration::from_secs(1));
+        match d.query_block("DATA?") {
+            Err(KsError::BlockSizeParse) => (),
+            other => panic!("{:?}", other),
+        }
+        drop(d);
+        e.join().unwrap().unwrap();
+
+        let (e, mut d) = faulty(Fault::NoTerminator, Duration::from_millis(200));
+        match d.query("*IDN?") {
+            Err(KsError::Timeout { consumed: 8 }) => (),
+            other => panic!("{:?}", other),
+        }
+        drop(d);
+        e.join().unwrap().unwrap();
+
+        let (e, mut d) = faulty(Fault::DropAfter(100), Duration::from_secs(1));
+        assert_eq!(d.query_f64("MEAS?").unwrap(), 1.5);
+        match d.query_block("DUMP?") {
+            Err(KsError::Disconnected { consumed: 84 }) => (),
+            other => panic!("{:?}", other),
+        }
+        drop(d);
+        e.join().unwrap().unwrap();
+
+        let (e, mut d) = faulty(Fault::Corrupt { seed: 7, rate: 0.01 }, Duration::from_secs(1));
+        let expected = (0..DUMP_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        match d.query_block("DUMP?") {
+            Ok(data) => assert_ne!(data, expected),
+            Err(err) => assert!(err.consumed().is_some(), "{:?}", err),
+        }
+        drop(d);
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn emulate_hislip() {
+        let e = HislipEmulator::new("localhost").unwrap();
+        let port = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let options = HislipOptions {
+                port,
+                read_timeout: Some(Duration::from_secs(1)),
+                ..HislipOptions::default()
+            };
+            let t = HislipTransport::new(String::from("localhost"), String::from("hislip0"), options);
+            let mut d = KsDevice::with_transport(t);
+            d.connect().unwrap();
+            assert_eq!(d.transport().version(), Some((1, 0)));
+
+            assert_eq!(d.query_str("*IDN?").unwrap(), "Emulator");
+            assert_eq!(d.query_str(&format!("*IDN?{:100}", "")).unwrap(), "Emulator");
+            assert_eq!(d.query_block("DATA?").unwrap(), vec![0, 255, 10, 128]);
+            assert_eq!(d.query_block("DATA0?").unwrap(), vec![0, 255, 13, 128]);
+            assert_eq!(d.query_block("DATA0:NL?").unwrap(), vec![1, b'\n', 2]);
+
+            let mut data = Vec::new();
+            d.send(b"DUMP?").unwrap();
+            assert_eq!(d.receive_block_into(&mut data, |_, _| ()).unwrap(), DUMP_SIZE);
+
+            d.write("FOO").unwrap();
+            assert_eq!(d.query_str("*IDN?").unwrap(), "Emulator");
+            assert_eq!(d.transport_mut().status_query().unwrap(), 0x04);
+            d.write("*CLS").unwrap();
+
+            d.send(b"DUMP?").unwrap();
+            d.transport_mut().device_clear().unwrap();
+            assert_eq!(d.query_str("*IDN?").unwrap(), "Emulator");
+
+            assert!(d.transport_mut().lock(Duration::from_secs(1), None).unwrap());
+            d.transport_mut().unlock().unwrap();
+            assert!(d.transport_mut().unlock().is_err());
+            d.lock_shared("station", Duration::from_secs(1)).unwrap().unlock().unwrap();
+
+            assert_eq!(d.transport_mut().wait_service_request(Some(Duration::from_millis(100))).unwrap(), None);
+            d.write("*SRE 32").unwrap();
+            d.write("*ESE 1").unwrap();
+            d.write("*OPC").unwrap();
+            assert_eq!(d.transport_mut().wait_service_request(Some(Duration::from_secs(1))).unwrap(), Some(0x60));
+
+            d.set_completion(Completion::ServiceRequest);
+            d.wait_complete(Duration::from_secs(1)).unwrap();
+            assert_eq!(d.query_i64("*SRE?").unwrap(), 32);
+
+            let rx = d.subscribe_service_requests().unwrap();
+            d.write("*OPC").unwrap();
+            assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), StatusByte::ESB | StatusByte::MSS);
+            assert_eq!(d.transport_mut().status_query().unwrap(), 0x60);
+            d.wait_complete(Duration::from_secs(1)).unwrap();
+
+            d.disconnect().unwrap();
+        }
+
+        e.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn indefinite_block_without_newline() {
+        let mut stream = &b"#0\x01\x02"[..];
+        let err = KsHook::read_from(&mut stream, &KsLimits::default()).unwrap_err();
+        assert_eq!(err.consumed(), Some(4));
+        match err {
+            KsError::Disconnected { .. } => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_errors() {
+        let limits = KsLimits::default();
+        match KsHook::read_from(&mut &b"#x\n"[..], &limits) {
+            Err(KsError::BadBlockHeader(b'x')) => (),
+            other => panic!("{:?}", other),
+        }
+        match KsHook::read_from(&mut &b"#2a1\n"[..], &limits) {
+            Err(KsError::BlockSizeParse) => (),
+            other => panic!("{:?}", other),
+        }
+        match KsHook::read_from(&mut &b"#12ab;\n"[..], &limits) {
+            Err(KsError::TrailingGarbage { consumed: 7 }) => (),
+            other => panic!("{:?}", other),
+        }
+        match KsHook::read_from(&mut &b"#15ab"[..], &limits) {
+            Err(KsError::Disconnected { consumed: 5 }) => (),
+            other => panic!("{:?}", other),
+        }
+        match KsHook::read_from(&mut &b"\xff\n"[..], &limits) {
+            Err(KsError::NonUtf8Text) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn limit_error(err: KsError) -> KsLimitError {
+        match err {
+            KsError::Limit(err) => err,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_limits() {
+        let limits = KsLimits { max_block_size: Some(4), max_line_length: Some(8) };
+
+        let mut stream = &b"#14abcd\n#15abcde\n#9999999999\n"[..];
+        assert_eq!(KsHook::read_from(&mut stream, &limits).unwrap(), KsData::from_bin(b"abcd".to_vec()));
+        assert_eq!(
+            limit_error(KsHook::read_from(&mut stream, &limits).unwrap_err()),
+            KsLimitError::BlockSize { size: Some(5), limit: 4 },
+        );
+        let mut stream = &b"#0abcde\n"[..];
+        assert_eq!(
+            limit_error(KsHook::read_from(&mut stream, &limits).unwrap_err()),
+            KsLimitError::BlockSize { size: None, limit: 4 },
+        );
+
+        let mut stream = &b"1234567\n12345678\n"[..];
+        assert_eq!(KsHook::read_from(&mut stream, &limits).unwrap(), KsData::from_text(String::from("1234567")));
+        assert_eq!(
+            limit_error(KsHook::read_from(&mut stream, &limits).unwrap_err()),
+            KsLimitError::LineLength { limit: 8 },
+        );
+    }
+
+    #[test]
+    fn emulate_limits() {
+        let e = Emulator::new(("localhost", 0)).unwrap();
+        let p = e.address().unwrap().port();
+        let e = e.run();
+
+        thread::sleep(Duration::from_millis(100));
+
+        {
+            let limits = KsLimits { max_block_size: Some(DUMP_SIZE - 1), ..KsLimits::default() };
+            let mut d = KsDevice::with_limits((String::from("localhost"), p), None, limits);
+            assert_eq!(d.limits(), &limits);
+            d.connect().unwrap();
+
+            d.send(b"DATA?").unwrap();
+            assert_eq!(d.receive().unwrap(), KsData::from_bin(vec![0, 255, 10, 128]));
+
+            d.send(b"DUMP?").unwrap();
+            assert_eq!(
+                limit_error(d.receive().unwrap_err()),
+                KsLimitError::BlockSize { size: Some(DUMP_SIZE), limit: DUMP_SIZE - 1 },
+            );
+        }
+
+        e.join().unwrap().unwrap();
+    }
 }
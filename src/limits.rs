@@ -0,0 +1,63 @@
+use std::error::Error;
+use std::fmt;
+
+
+/// Default maximum size of binary block read into memory, 256 MiB
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 256 << 20;
+/// Default maximum length of text response line, 16 MiB
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 16 << 20;
+
+/// Limits protecting from unbounded allocation when reading responses
+///
+/// `None` means no limit. Streaming reads into a writer are not affected by `max_block_size`
+/// because they don't allocate memory for the block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KsLimits {
+    /// Maximum size of binary block content in bytes
+    pub max_block_size: Option<usize>,
+    /// Maximum length of text response in bytes including terminating newline
+    pub max_line_length: Option<usize>,
+}
+
+impl KsLimits {
+    /// No limits at all
+    pub fn unlimited() -> Self {
+        Self { max_block_size: None, max_line_length: None }
+    }
+}
+
+impl Default for KsLimits {
+    fn default() -> Self {
+        Self {
+            max_block_size: Some(DEFAULT_MAX_BLOCK_SIZE),
+            max_line_length: Some(DEFAULT_MAX_LINE_LENGTH),
+        }
+    }
+}
+
+/// Response exceeds one of [`KsLimits`], see [`KsError::Limit`](crate::KsError::Limit)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KsLimitError {
+    /// Binary block is larger than `max_block_size`, `size` is known for definite blocks only
+    BlockSize { size: Option<usize>, limit: usize },
+    /// Text line has no newline within `max_line_length` bytes
+    LineLength { limit: usize },
+}
+
+impl fmt::Display for KsLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KsLimitError::BlockSize { size: Some(size), limit } => {
+                write!(f, "block size {} exceeds limit {}", size, limit)
+            },
+            KsLimitError::BlockSize { size: None, limit } => {
+                write!(f, "indefinite block exceeds size limit {}", limit)
+            },
+            KsLimitError::LineLength { limit } => {
+                write!(f, "text line exceeds length limit {}", limit)
+            },
+        }
+    }
+}
+
+impl Error for KsLimitError {}
@@ -0,0 +1,46 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::{KsDevice, KsResult, Transport};
+
+
+/// Lock held on instrument, it is released when guard is dropped
+///
+/// Device is accessed through the guard while lock is held.
+pub struct KsLockGuard<'a, T: Transport> {
+    device: &'a mut KsDevice<T>,
+    released: bool,
+}
+
+impl<'a, T: Transport> KsLockGuard<'a, T> {
+    pub(crate) fn new(device: &'a mut KsDevice<T>) -> Self {
+        Self { device, released: false }
+    }
+
+    /// Release lock reporting error, dropped guard ignores it
+    pub fn unlock(mut self) -> KsResult<()> {
+        self.released = true;
+        self.device.unlock()
+    }
+}
+
+impl<'a, T: Transport> Deref for KsLockGuard<'a, T> {
+    type Target = KsDevice<T>;
+
+    fn deref(&self) -> &KsDevice<T> {
+        self.device
+    }
+}
+
+impl<'a, T: Transport> DerefMut for KsLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut KsDevice<T> {
+        self.device
+    }
+}
+
+impl<'a, T: Transport> Drop for KsLockGuard<'a, T> {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.device.unlock();
+        }
+    }
+}
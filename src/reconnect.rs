@@ -0,0 +1,87 @@
+use std::time::{Duration};
+
+
+/// Policy of restoring connection lost in the middle of communication
+///
+/// The first attempt is made immediately, each following one waits for the delay
+/// that starts at `initial_delay` and grows by `backoff` factor up to `max_delay`.
+/// `backoff` less than `1.0` or NaN is treated as `1.0`, i.e. constant delay.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub backoff: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            backoff: 2.0,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delays before each attempt
+    pub fn delays(&self) -> impl Iterator<Item = Duration> + '_ {
+        // `max` returns the other operand for NaN
+        let backoff = self.backoff.max(1.0);
+        let mut next = self.initial_delay;
+        (0..self.max_attempts).map(move |i| {
+            if i == 0 {
+                return Duration::from_secs(0);
+            }
+            let delay = next.min(self.max_delay);
+            let secs = next.as_secs_f64() * backoff;
+            next = if secs < self.max_delay.as_secs_f64() {
+                Duration::from_secs_f64(secs)
+            } else {
+                self.max_delay
+            };
+            delay
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays() {
+        let policy = ReconnectPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            backoff: 2.0,
+        };
+        assert_eq!(
+            policy.delays().map(|d| d.as_millis()).collect::<Vec<_>>(),
+            vec![0, 100, 200, 300, 300],
+        );
+        assert_eq!(ReconnectPolicy { max_attempts: 0, ..policy }.delays().count(), 0);
+    }
+
+    #[test]
+    fn bad_backoff() {
+        for &backoff in &[-2.0, f64::NAN, f64::INFINITY, 1e300] {
+            let policy = ReconnectPolicy {
+                max_attempts: 4,
+                initial_delay: Duration::from_millis(100),
+                max_delay: Duration::from_millis(300),
+                backoff,
+            };
+            let delays = policy.delays().map(|d| d.as_millis()).collect::<Vec<_>>();
+            if backoff < 1.0 || backoff.is_nan() {
+                assert_eq!(delays, vec![0, 100, 100, 100]);
+            } else {
+                assert_eq!(delays, vec![0, 100, 300, 300]);
+            }
+        }
+    }
+}
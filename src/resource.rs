@@ -0,0 +1,347 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration};
+
+use crate::{
+    KsDevice, KsResult, Transport,
+    TcpTransport, TcpOptions, Vxi11Transport, Vxi11Options, HislipTransport, HislipOptions,
+};
+
+
+/// Default VXI-11 logical device name
+pub const DEFAULT_VXI11_DEVICE: &str = "inst0";
+
+/// Device opened by resource string with transport selected at runtime
+pub type KsDynDevice = KsDevice<Box<dyn Transport + Send>>;
+
+/// VISA-style resource string of LAN instrument
+///
+/// Supported forms are `TCPIP[board]::host::port::SOCKET`,
+/// `TCPIP[board]::host[::device][::INSTR]` for VXI-11
+/// and `TCPIP[board]::host::hislipN[,port][::INSTR]` for HiSLIP.
+/// Interface and class names are case-insensitive.
+/// IPv6 host is given in brackets, e.g. `TCPIP::[fe80::1]::INSTR`, and stored without them.
+/// `Display` produces canonical form that parses back to the same value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ResourceName {
+    /// Raw SCPI socket
+    Socket { board: u32, host: String, port: u16 },
+    /// VXI-11 core channel, `device` is `inst0` when omitted
+    Vxi11 { board: u32, host: String, device: String },
+    /// HiSLIP session, default port is used when `port` is `None`
+    Hislip { board: u32, host: String, sub_address: String, port: Option<u16> },
+}
+
+/// Resource string is malformed or not supported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResourceError {
+    /// Interface other than `TCPIP`
+    UnsupportedInterface(String),
+    /// Board number after interface name is not a number
+    InvalidBoard(String),
+    /// Host part is missing or empty
+    EmptyHost,
+    /// Port is not a number in range
+    InvalidPort(String),
+    /// Resource class other than `INSTR` or `SOCKET`
+    UnsupportedClass(String),
+    /// Wrong number of `::`-separated parts or empty part
+    InvalidFormat(String),
+}
+
+impl fmt::Display for ResourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResourceError::UnsupportedInterface(ref s) => write!(f, "unsupported interface {:?}", s),
+            ResourceError::InvalidBoard(ref s) => write!(f, "invalid board number {:?}", s),
+            ResourceError::EmptyHost => write!(f, "empty host"),
+            ResourceError::InvalidPort(ref s) => write!(f, "invalid port {:?}", s),
+            ResourceError::UnsupportedClass(ref s) => write!(f, "unsupported resource class {:?}", s),
+            ResourceError::InvalidFormat(ref s) => write!(f, "invalid resource string {:?}", s),
+        }
+    }
+}
+
+impl Error for ResourceError {}
+
+/// Split on `::` keeping bracketed IPv6 address like `[fe80::1]` in one part
+fn split_parts(s: &str) -> Vec<&str> {
+    let bytes = s.as_bytes();
+    let mut parts = Vec::new();
+    let (mut start, mut pos, mut bracket) = (0, 0, false);
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'[' => bracket = true,
+            b']' => bracket = false,
+            b':' if !bracket && bytes.get(pos + 1) == Some(&b':') => {
+                parts.push(&s[start..pos]);
+                pos += 2;
+                start = pos;
+                continue;
+            },
+            _ => (),
+        }
+        pos += 1;
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Host as written in resource string, IPv6 address is put in brackets
+fn host_part(host: &str) -> Cow<'_, str> {
+    if host.contains(':') {
+        Cow::from(format!("[{}]", host))
+    } else {
+        Cow::from(host)
+    }
+}
+
+fn parse_port(s: &str) -> Result<u16, ResourceError> {
+    match s.parse() {
+        Ok(0) | Err(_) => Err(ResourceError::InvalidPort(String::from(s))),
+        Ok(port) => Ok(port),
+    }
+}
+
+impl ResourceName {
+    pub fn parse(s: &str) -> Result<Self, ResourceError> {
+        let mut parts = split_parts(s);
+        if parts.iter().any(|p| p.is_empty()) && parts.len() > 1 {
+            return Err(ResourceError::InvalidFormat(String::from(s)));
+        }
+
+        let interface = parts.remove(0);
+        match interface.get(..5) {
+            Some(name) if name.eq_ignore_ascii_case("TCPIP") => (),
+            _ => return Err(ResourceError::UnsupportedInterface(String::from(interface))),
+        }
+        let board = match &interface[5..] {
+            "" => 0,
+            num => num.parse().map_err(|_| ResourceError::InvalidBoard(String::from(num)))?,
+        };
+
+        if parts.is_empty() {
+            return Err(ResourceError::EmptyHost);
+        }
+        let host = parts.remove(0);
+        let host = match host.strip_prefix('[') {
+            Some(inner) => inner.strip_suffix(']').filter(|h| !h.is_empty())
+            .ok_or_else(|| ResourceError::InvalidFormat(String::from(s)))?,
+            None => host,
+        };
+        let host = String::from(host);
+
+        let class = match parts.last() {
+            Some(class) if class.eq_ignore_ascii_case("SOCKET") || class.eq_ignore_ascii_case("INSTR") => {
+                parts.pop()
+            },
+            Some(class) if parts.len() > 1 => {
+                return Err(ResourceError::UnsupportedClass(String::from(*class)));
+            },
+            _ => None,
+        };
+
+        match (class, parts.as_slice()) {
+            (Some(class), &[port]) if class.eq_ignore_ascii_case("SOCKET") => {
+                Ok(ResourceName::Socket { board, host, port: parse_port(port)? })
+            },
+            (Some(class), _) if class.eq_ignore_ascii_case("SOCKET") => {
+                Err(ResourceError::InvalidFormat(String::from(s)))
+            },
+            (_, &[]) => {
+                Ok(ResourceName::Vxi11 { board, host, device: String::from(DEFAULT_VXI11_DEVICE) })
+            },
+            (_, &[device]) if device.to_ascii_lowercase().starts_with("hislip") => {
+                let (sub_address, port) = match device.find(',') {
+                    Some(pos) => (&device[..pos], Some(parse_port(&device[pos + 1..])?)),
+                    None => (device, None),
+                };
+                Ok(ResourceName::Hislip { board, host, sub_address: String::from(sub_address), port })
+            },
+            (_, &[device]) => {
+                Ok(ResourceName::Vxi11 { board, host, device: String::from(device) })
+            },
+            _ => Err(ResourceError::InvalidFormat(String::from(s))),
+        }
+    }
+
+    pub fn board(&self) -> u32 {
+        match *self {
+            ResourceName::Socket { board, .. } |
+            ResourceName::Vxi11 { board, .. } |
+            ResourceName::Hislip { board, .. } => board,
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        match *self {
+            ResourceName::Socket { ref host, .. } |
+            ResourceName::Vxi11 { ref host, .. } |
+            ResourceName::Hislip { ref host, .. } => host,
+        }
+    }
+
+    /// Create transport for this resource, not connected yet
+    pub fn transport(&self, timeout: Option<Duration>) -> Box<dyn Transport + Send> {
+        match *self {
+            ResourceName::Socket { ref host, port, .. } => {
+                Box::new(TcpTransport::new((host.clone(), port), TcpOptions::with_timeout(timeout)))
+            },
+            ResourceName::Vxi11 { ref host, ref device, .. } => {
+                let options = Vxi11Options {
+                    connect_timeout: timeout,
+                    read_timeout: timeout,
+                    write_timeout: timeout,
+                    ..Vxi11Options::default()
+                };
+                Box::new(Vxi11Transport::new(host.clone(), device.clone(), options))
+            },
+            ResourceName::Hislip { ref host, ref sub_address, port, .. } => {
+                let mut options = HislipOptions {
+                    connect_timeout: timeout,
+                    read_timeout: timeout,
+                    write_timeout: timeout,
+                    ..HislipOptions::default()
+                };
+                if let Some(port) = port {
+                    options.port = port;
+                }
+                Box::new(HislipTransport::new(host.clone(), sub_address.clone(), options))
+            },
+        }
+    }
+
+    /// Create device for this resource and connect to it
+    pub fn open(&self, timeout: Option<Duration>) -> KsResult<KsDynDevice> {
+        let mut device = KsDevice::with_transport(self.transport(timeout));
+        device.connect()?;
+        Ok(device)
+    }
+}
+
+impl FromStr for ResourceName {
+    type Err = ResourceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for ResourceName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResourceName::Socket { board, ref host, port } => {
+                write!(f, "TCPIP{}::{}::{}::SOCKET", board, host_part(host), port)
+            },
+            ResourceName::Vxi11 { board, ref host, ref device } => {
+                write!(f, "TCPIP{}::{}::{}::INSTR", board, host_part(host), device)
+            },
+            ResourceName::Hislip { board, ref host, ref sub_address, port: Some(port) } => {
+                write!(f, "TCPIP{}::{}::{},{}::INSTR", board, host_part(host), sub_address, port)
+            },
+            ResourceName::Hislip { board, ref host, ref sub_address, port: None } => {
+                write!(f, "TCPIP{}::{}::{}::INSTR", board, host_part(host), sub_address)
+            },
+        }
+    }
+}
+
+/// Parse resource string and connect to device using suitable transport
+pub fn open(resource: &str) -> KsResult<KsDynDevice> {
+    open_timeout(resource, None)
+}
+
+/// Same as [`open`] with timeout of connecting, reading and writing
+pub fn open_timeout(resource: &str, timeout: Option<Duration>) -> KsResult<KsDynDevice> {
+    ResourceName::parse(resource)?.open(timeout)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> ResourceName {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_forms() {
+        assert_eq!(
+            parse("TCPIP0::192.168.1.5::inst0::INSTR"),
+            ResourceName::Vxi11 { board: 0, host: "192.168.1.5".into(), device: "inst0".into() },
+        );
+        assert_eq!(
+            parse("tcpip::host::5025::socket"),
+            ResourceName::Socket { board: 0, host: "host".into(), port: 5025 },
+        );
+        assert_eq!(
+            parse("TCPIP::host::hislip0::INSTR"),
+            ResourceName::Hislip { board: 0, host: "host".into(), sub_address: "hislip0".into(), port: None },
+        );
+        assert_eq!(
+            parse("TCPIP2::host::hislip1,4881"),
+            ResourceName::Hislip { board: 2, host: "host".into(), sub_address: "hislip1".into(), port: Some(4881) },
+        );
+        assert_eq!(
+            parse("TCPIP::host::INSTR"),
+            ResourceName::Vxi11 { board: 0, host: "host".into(), device: "inst0".into() },
+        );
+        assert_eq!(
+            parse("TCPIP::host"),
+            ResourceName::Vxi11 { board: 0, host: "host".into(), device: "inst0".into() },
+        );
+        assert_eq!(
+            parse("TCPIP::host::gpib0,5"),
+            ResourceName::Vxi11 { board: 0, host: "host".into(), device: "gpib0,5".into() },
+        );
+    }
+
+    #[test]
+    fn parse_ipv6() {
+        assert_eq!(
+            parse("TCPIP::[fe80::1]::inst0::INSTR"),
+            ResourceName::Vxi11 { board: 0, host: "fe80::1".into(), device: "inst0".into() },
+        );
+        assert_eq!(
+            parse("TCPIP0::[::1]::5025::SOCKET"),
+            ResourceName::Socket { board: 0, host: "::1".into(), port: 5025 },
+        );
+        assert_eq!(
+            parse("TCPIP::[2001:db8::5]::hislip0"),
+            ResourceName::Hislip { board: 0, host: "2001:db8::5".into(), sub_address: "hislip0".into(), port: None },
+        );
+        assert_eq!(parse("TCPIP::[fe80::1]").to_string(), "TCPIP0::[fe80::1]::inst0::INSTR");
+        assert!(matches!(ResourceName::parse("TCPIP::[fe80::1::INSTR"), Err(ResourceError::InvalidFormat(_))));
+        assert!(matches!(ResourceName::parse("TCPIP::[]::INSTR"), Err(ResourceError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(ResourceName::parse("GPIB0::5::INSTR"), Err(ResourceError::UnsupportedInterface("GPIB0".into())));
+        assert_eq!(ResourceName::parse("TCPIPx::host"), Err(ResourceError::InvalidBoard("x".into())));
+        assert_eq!(ResourceName::parse("TCPIP0"), Err(ResourceError::EmptyHost));
+        assert_eq!(ResourceName::parse("TCPIP0::host::99999::SOCKET"), Err(ResourceError::InvalidPort("99999".into())));
+        assert_eq!(ResourceName::parse("TCPIP0::host::hislip0,x"), Err(ResourceError::InvalidPort("x".into())));
+        assert_eq!(ResourceName::parse("TCPIP0::host::inst0::BACKPLANE"), Err(ResourceError::UnsupportedClass("BACKPLANE".into())));
+        assert!(matches!(ResourceName::parse("TCPIP0::host::SOCKET"), Err(ResourceError::InvalidFormat(_))));
+        assert!(matches!(ResourceName::parse("TCPIP0::::inst0"), Err(ResourceError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        for s in &[
+            "TCPIP0::192.168.1.5::inst0::INSTR",
+            "TCPIP::host::5025::SOCKET",
+            "TCPIP::host::hislip0::INSTR",
+            "tcpip3::host::hislip0,4880",
+            "TCPIP::host",
+        ] {
+            let name = parse(s);
+            assert_eq!(parse(&name.to_string()), name);
+        }
+        assert_eq!(parse("tcpip::host::5025::socket").to_string(), "TCPIP0::host::5025::SOCKET");
+    }
+}
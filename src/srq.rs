@@ -0,0 +1,99 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender, Receiver};
+
+use crate::{StatusByte};
+
+
+type Callback = Arc<Mutex<dyn FnMut(StatusByte) + Send>>;
+
+enum Subscriber {
+    Channel(Sender<StatusByte>),
+    Callback(Callback),
+}
+
+/// Subscribers of service request notifications shared with transport listener threads
+///
+/// Cloned dispatcher refers to the same subscribers.
+#[derive(Clone, Default)]
+pub struct SrqDispatcher {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl SrqDispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribe with channel, it is unsubscribed when receiver is dropped
+    pub fn subscribe(&self) -> Receiver<StatusByte> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(Subscriber::Channel(tx));
+        rx
+    }
+
+    /// Subscribe with callback called from the thread that received request
+    ///
+    /// Callback may subscribe to the same dispatcher, subscribers list is not locked while it runs.
+    pub fn add_callback<F>(&self, callback: F)
+    where F: FnMut(StatusByte) + Send + 'static {
+        self.subscribers.lock().unwrap().push(Subscriber::Callback(Arc::new(Mutex::new(callback))));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.lock().unwrap().is_empty()
+    }
+
+    /// Notify all subscribers about service request with given status byte
+    pub fn dispatch(&self, stb: StatusByte) {
+        let mut callbacks = Vec::new();
+        self.subscribers.lock().unwrap().retain(|subscriber| match *subscriber {
+            Subscriber::Channel(ref tx) => tx.send(stb).is_ok(),
+            Subscriber::Callback(ref callback) => {
+                callbacks.push(callback.clone());
+                true
+            },
+        });
+        for callback in callbacks {
+            (callback.lock().unwrap())(stb);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatch() {
+        let dispatcher = SrqDispatcher::new();
+        let rx = dispatcher.subscribe();
+        let count = Arc::new(Mutex::new(0));
+        {
+            let count = count.clone();
+            dispatcher.clone().add_callback(move |_| *count.lock().unwrap() += 1);
+        }
+        dispatcher.dispatch(StatusByte::MSS);
+        assert_eq!(rx.try_recv().unwrap(), StatusByte::MSS);
+        drop(rx);
+        dispatcher.dispatch(StatusByte::MSS | StatusByte::ESB);
+        assert_eq!(*count.lock().unwrap(), 2);
+        assert!(!dispatcher.is_empty());
+    }
+
+    #[test]
+    fn subscribe_from_callback() {
+        let dispatcher = SrqDispatcher::new();
+        let receivers = Arc::new(Mutex::new(Vec::new()));
+        {
+            let (dispatcher, receivers) = (dispatcher.clone(), receivers.clone());
+            dispatcher.clone().add_callback(move |_| receivers.lock().unwrap().push(dispatcher.subscribe()));
+        }
+        dispatcher.dispatch(StatusByte::MSS);
+        dispatcher.dispatch(StatusByte::MSS | StatusByte::ESB);
+        let receivers = receivers.lock().unwrap();
+        assert_eq!(receivers.len(), 2);
+        assert_eq!(receivers[0].try_recv().unwrap(), StatusByte::MSS | StatusByte::ESB);
+        assert!(receivers[1].try_recv().is_err());
+    }
+}
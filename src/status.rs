@@ -0,0 +1,172 @@
+use bitflags::{bitflags, Flags};
+
+
+bitflags! {
+    /// Status byte read with `*STB?`, also used as service request enable mask
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct StatusByte: u8 {
+        /// Error queue is not empty
+        const ERROR_QUEUE = 0x04;
+        /// Questionable status summary
+        const QUES = 0x08;
+        /// Message available in output queue
+        const MAV = 0x10;
+        /// Standard event status summary
+        const ESB = 0x20;
+        /// Master summary status, instrument requests service
+        const MSS = 0x40;
+        /// Operation status summary
+        const OPER = 0x80;
+    }
+}
+
+bitflags! {
+    /// Standard event status register read with `*ESR?`, also used as `*ESE` mask
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct EventStatus: u8 {
+        /// Operation complete after `*OPC`
+        const OPC = 0x01;
+        /// Request control
+        const RQC = 0x02;
+        /// Query error, e.g. response was not read
+        const QYE = 0x04;
+        /// Device-dependent error
+        const DDE = 0x08;
+        /// Execution error
+        const EXE = 0x10;
+        /// Command error
+        const CME = 0x20;
+        /// User request
+        const URQ = 0x40;
+        /// Power on
+        const PON = 0x80;
+    }
+}
+
+bitflags! {
+    /// SCPI `STATus:OPERation` condition, event or enable register
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct OperationStatus: u16 {
+        const CALIBRATING = 0x0001;
+        const SETTLING = 0x0002;
+        const RANGING = 0x0004;
+        const SWEEPING = 0x0008;
+        const MEASURING = 0x0010;
+        const WAITING_TRIGGER = 0x0020;
+        const WAITING_ARM = 0x0040;
+        const CORRECTING = 0x0080;
+        /// Summary of instrument-specific subregisters
+        const INSTRUMENT = 0x2000;
+        const PROGRAM_RUNNING = 0x4000;
+    }
+}
+
+bitflags! {
+    /// SCPI `STATus:QUEStionable` condition, event or enable register
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct QuestionableStatus: u16 {
+        const VOLTAGE = 0x0001;
+        const CURRENT = 0x0002;
+        const TIME = 0x0004;
+        const POWER = 0x0008;
+        const TEMPERATURE = 0x0010;
+        const FREQUENCY = 0x0020;
+        const PHASE = 0x0040;
+        const MODULATION = 0x0080;
+        const CALIBRATION = 0x0100;
+        /// Summary of instrument-specific subregisters
+        const INSTRUMENT = 0x2000;
+        const COMMAND_WARNING = 0x4000;
+    }
+}
+
+/// Register of IEEE 488.2 / SCPI status model
+pub trait StatusRegister: Flags + Copy + 'static where u32: From<Self::Bits> {
+    /// Meaning of each defined bit
+    const DESCRIPTIONS: &'static [(Self, &'static str)];
+
+    /// Meanings of bits set in register, undefined bits are named by their numbers
+    fn explain(&self) -> Vec<String> {
+        let mut items = Self::DESCRIPTIONS.iter()
+        .filter(|(bit, _)| self.contains(*bit))
+        .map(|(_, desc)| String::from(*desc))
+        .collect::<Vec<_>>();
+        let rest = u32::from(self.difference(Self::all()).bits());
+        items.extend((0..32).filter(|i| rest & (1 << i) != 0).map(|i| format!("bit {}", i)));
+        items
+    }
+}
+
+impl StatusRegister for StatusByte {
+    const DESCRIPTIONS: &'static [(Self, &'static str)] = &[
+        (StatusByte::ERROR_QUEUE, "error queue is not empty"),
+        (StatusByte::QUES, "questionable status summary"),
+        (StatusByte::MAV, "message available"),
+        (StatusByte::ESB, "standard event status summary"),
+        (StatusByte::MSS, "service requested"),
+        (StatusByte::OPER, "operation status summary"),
+    ];
+}
+
+impl StatusRegister for EventStatus {
+    const DESCRIPTIONS: &'static [(Self, &'static str)] = &[
+        (EventStatus::OPC, "operation complete"),
+        (EventStatus::RQC, "request control"),
+        (EventStatus::QYE, "query error"),
+        (EventStatus::DDE, "device-dependent error"),
+        (EventStatus::EXE, "execution error"),
+        (EventStatus::CME, "command error"),
+        (EventStatus::URQ, "user request"),
+        (EventStatus::PON, "power on"),
+    ];
+}
+
+impl StatusRegister for OperationStatus {
+    const DESCRIPTIONS: &'static [(Self, &'static str)] = &[
+        (OperationStatus::CALIBRATING, "calibrating"),
+        (OperationStatus::SETTLING, "settling"),
+        (OperationStatus::RANGING, "ranging"),
+        (OperationStatus::SWEEPING, "sweeping"),
+        (OperationStatus::MEASURING, "measuring"),
+        (OperationStatus::WAITING_TRIGGER, "waiting for trigger"),
+        (OperationStatus::WAITING_ARM, "waiting for arm"),
+        (OperationStatus::CORRECTING, "correcting"),
+        (OperationStatus::INSTRUMENT, "instrument summary"),
+        (OperationStatus::PROGRAM_RUNNING, "program running"),
+    ];
+}
+
+impl StatusRegister for QuestionableStatus {
+    const DESCRIPTIONS: &'static [(Self, &'static str)] = &[
+        (QuestionableStatus::VOLTAGE, "voltage"),
+        (QuestionableStatus::CURRENT, "current"),
+        (QuestionableStatus::TIME, "time"),
+        (QuestionableStatus::POWER, "power"),
+        (QuestionableStatus::TEMPERATURE, "temperature"),
+        (QuestionableStatus::FREQUENCY, "frequency"),
+        (QuestionableStatus::PHASE, "phase"),
+        (QuestionableStatus::MODULATION, "modulation"),
+        (QuestionableStatus::CALIBRATION, "calibration"),
+        (QuestionableStatus::INSTRUMENT, "instrument summary"),
+        (QuestionableStatus::COMMAND_WARNING, "command warning"),
+    ];
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explain() {
+        assert!(StatusByte::empty().explain().is_empty());
+        assert_eq!(
+            (EventStatus::OPC | EventStatus::CME).explain(),
+            vec!["operation complete", "command error"],
+        );
+        assert_eq!(
+            OperationStatus::from_bits_retain(0x0110).explain(),
+            vec!["measuring", "bit 8"],
+        );
+    }
+}
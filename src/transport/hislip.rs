@@ -0,0 +1,617 @@
+//! HiSLIP (IVI-6.1) client with synchronous and asynchronous channels
+
+use std::io::prelude::*;
+use std::io::{self};
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::net::{TcpStream, ToSocketAddrs, Shutdown};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration};
+
+use crate::{KsError, KsResult, StatusByte, SrqDispatcher};
+use super::{Transport};
+
+
+/// Default HiSLIP port
+pub const HISLIP_PORT: u16 = 4880;
+
+pub const INITIALIZE: u8 = 0;
+pub const INITIALIZE_RESPONSE: u8 = 1;
+pub const FATAL_ERROR: u8 = 2;
+pub const ERROR: u8 = 3;
+pub const ASYNC_LOCK: u8 = 4;
+pub const ASYNC_LOCK_RESPONSE: u8 = 5;
+pub const DATA: u8 = 6;
+pub const DATA_END: u8 = 7;
+pub const DEVICE_CLEAR_COMPLETE: u8 = 8;
+pub const DEVICE_CLEAR_ACKNOWLEDGE: u8 = 9;
+pub const ASYNC_MAXIMUM_MESSAGE_SIZE: u8 = 15;
+pub const ASYNC_MAXIMUM_MESSAGE_SIZE_RESPONSE: u8 = 16;
+pub const ASYNC_INITIALIZE: u8 = 17;
+pub const ASYNC_INITIALIZE_RESPONSE: u8 = 18;
+pub const ASYNC_DEVICE_CLEAR: u8 = 19;
+pub const ASYNC_SERVICE_REQUEST: u8 = 20;
+pub const ASYNC_STATUS_QUERY: u8 = 21;
+pub const ASYNC_STATUS_RESPONSE: u8 = 22;
+pub const ASYNC_DEVICE_CLEAR_ACKNOWLEDGE: u8 = 23;
+
+/// Control code of `AsyncLock` requesting the lock
+pub const LOCK_REQUEST: u8 = 1;
+/// Control code of `AsyncLock` releasing the lock
+pub const LOCK_RELEASE: u8 = 0;
+pub const LOCK_FAILURE: u8 = 0;
+pub const LOCK_SUCCESS: u8 = 1;
+pub const LOCK_SUCCESS_SHARED: u8 = 2;
+pub const LOCK_ERROR: u8 = 3;
+
+/// First message ID of session and after device clear
+pub const INITIAL_MESSAGE_ID: u32 = 0xffff_ff00;
+
+/// Control code bit telling that previous response was completely received
+const RMT_DELIVERED: u8 = 1;
+/// Vendor ID sent to server
+const VENDOR_ID: [u8; 2] = *b"KL";
+/// Upper bound of payload of non-data messages
+const MAX_CONTROL_PAYLOAD: u64 = 1 << 20;
+const HEADER_SIZE: usize = 16;
+
+/// Header of HiSLIP message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub kind: u8,
+    pub control: u8,
+    pub param: u32,
+    pub len: u64,
+}
+
+impl Header {
+    pub fn new(kind: u8, control: u8, param: u32, len: usize) -> Self {
+        Self { kind, control, param, len: len as u64 }
+    }
+
+    pub fn read_from<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut buf = [0; HEADER_SIZE];
+        stream.read_exact(&mut buf)?;
+        if &buf[..2] != b"HS" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "hislip: bad message prologue"));
+        }
+        let mut param = [0; 4];
+        param.copy_from_slice(&buf[4..8]);
+        let mut len = [0; 8];
+        len.copy_from_slice(&buf[8..]);
+        Ok(Self {
+            kind: buf[2],
+            control: buf[3],
+            param: u32::from_be_bytes(param),
+            len: u64::from_be_bytes(len),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0; HEADER_SIZE];
+        buf[..2].copy_from_slice(b"HS");
+        buf[2] = self.kind;
+        buf[3] = self.control;
+        buf[4..8].copy_from_slice(&self.param.to_be_bytes());
+        buf[8..].copy_from_slice(&self.len.to_be_bytes());
+        buf
+    }
+
+    /// Read payload of non-data message
+    pub fn read_payload<R: Read>(&self, stream: &mut R) -> io::Result<Vec<u8>> {
+        if self.len > MAX_CONTROL_PAYLOAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "hislip: message is too large"));
+        }
+        let mut payload = vec![0; self.len as usize];
+        stream.read_exact(&mut payload)?;
+        Ok(payload)
+    }
+}
+
+/// Write complete message
+pub fn write_message<W: Write>(stream: &mut W, kind: u8, control: u8, param: u32, payload: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&Header::new(kind, control, param, payload.len()).to_bytes());
+    buf.extend_from_slice(payload);
+    stream.write_all(&buf)
+    .and_then(|()| stream.flush())
+}
+
+/// Error reported by `Error` or `FatalError` message
+fn server_error(header: &Header, payload: &[u8]) -> io::Error {
+    let kind = if header.kind == FATAL_ERROR { "fatal error" } else { "error" };
+    io::Error::new(io::ErrorKind::Other, format!(
+        "hislip: {} {}, {}", kind, header.control, String::from_utf8_lossy(payload),
+    ))
+}
+
+fn unexpected(header: &Header) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("hislip: unexpected message type {}", header.kind))
+}
+
+fn unexpected_control(header: &Header) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("hislip: unexpected control code {}", header.control))
+}
+
+fn millis(timeout: Duration) -> u32 {
+    min(timeout.as_millis(), u32::MAX as u128) as u32
+}
+
+/// Options of HiSLIP connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HislipOptions {
+    pub port: u16,
+    /// Highest protocol version offered to server as `(major, minor)`
+    pub version: (u8, u8),
+    pub connect_timeout: Option<Duration>,
+    /// Timeout of reading synchronous channel and waiting for asynchronous responses
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    /// Maximum size of message client is able to receive
+    pub max_message_size: u64,
+}
+
+impl Default for HislipOptions {
+    fn default() -> Self {
+        Self {
+            port: HISLIP_PORT,
+            version: (2, 0),
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            max_message_size: 1 << 20,
+        }
+    }
+}
+
+/// Message with its payload
+type Message = (Header, Vec<u8>);
+
+struct HislipSession {
+    sync: TcpStream,
+    asyn: TcpStream,
+    session_id: u16,
+    version: (u8, u8),
+    overlapped: bool,
+    /// Maximum size of message server is able to receive
+    max_message_size: u64,
+    message_id: u32,
+    /// Complete response was received since the last message sent
+    rmt_delivered: bool,
+    /// Unread payload of current data message
+    remaining: u64,
+    /// Current data message is `DataEnd`
+    end: bool,
+    /// Status bytes of service requests received while waiting for other responses
+    service_requests: VecDeque<u8>,
+    /// Messages of asynchronous channel forwarded by listener thread, if it is running
+    listener: Option<Receiver<io::Result<Message>>>,
+}
+
+impl HislipSession {
+    /// Read message from asynchronous channel directly or from listener thread
+    fn read_async(&mut self, timeout: Option<Duration>) -> io::Result<Message> {
+        match self.listener {
+            Some(ref listener) => {
+                let res = match timeout {
+                    Some(to) => listener.recv_timeout(to),
+                    None => listener.recv().map_err(RecvTimeoutError::from),
+                };
+                match res {
+                    Ok(msg) => msg,
+                    Err(RecvTimeoutError::Timeout) => Err(io::ErrorKind::TimedOut.into()),
+                    Err(RecvTimeoutError::Disconnected) => Err(io::ErrorKind::UnexpectedEof.into()),
+                }
+            },
+            None => {
+                self.asyn.set_read_timeout(timeout)?;
+                let header = Header::read_from(&mut self.asyn)?;
+                let payload = header.read_payload(&mut self.asyn)?;
+                Ok((header, payload))
+            },
+        }
+    }
+
+    /// Read asynchronous channel in background thread notifying `dispatcher` about service requests
+    ///
+    /// All messages are still forwarded to the session, so waiting for responses works as before.
+    fn start_listener(&mut self, dispatcher: SrqDispatcher) -> io::Result<()> {
+        let mut stream = self.asyn.try_clone()?;
+        stream.set_read_timeout(None)?;
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || loop {
+            let msg = Header::read_from(&mut stream)
+            .and_then(|header| header.read_payload(&mut stream).map(|payload| (header, payload)));
+            let failed = match msg {
+                Ok((ref header, _)) => {
+                    if header.kind == ASYNC_SERVICE_REQUEST {
+                        dispatcher.dispatch(StatusByte::from_bits_retain(header.control));
+                    }
+                    false
+                },
+                Err(_) => true,
+            };
+            if tx.send(msg).is_err() || failed {
+                break;
+            }
+        });
+        self.listener = Some(rx);
+        Ok(())
+    }
+
+    /// Send message over asynchronous channel and wait for response of given type
+    fn exchange(&mut self, kind: u8, control: u8, param: u32, payload: &[u8], response: u8, timeout: Option<Duration>) -> io::Result<Message> {
+        write_message(&mut self.asyn, kind, control, param, payload)?;
+        loop {
+            let (header, payload) = self.read_async(timeout)?;
+            match header.kind {
+                k if k == response => break Ok((header, payload)),
+                ASYNC_SERVICE_REQUEST => self.service_requests.push_back(header.control),
+                ERROR | FATAL_ERROR => break Err(server_error(&header, &payload)),
+                _ => break Err(unexpected(&header)),
+            }
+        }
+    }
+
+    /// Skip the rest of data message being read
+    fn skip_remaining(&mut self) -> io::Result<()> {
+        io::copy(&mut (&mut self.sync).take(self.remaining), &mut io::sink())?;
+        self.remaining = 0;
+        Ok(())
+    }
+}
+
+impl Drop for HislipSession {
+    fn drop(&mut self) {
+        // Listener thread holds a clone of the stream and would keep connection open
+        if self.listener.is_some() {
+            let _ = self.asyn.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// HiSLIP transport, supports device clear, locking and service requests
+pub struct HislipTransport {
+    host: String,
+    sub_address: String,
+    options: HislipOptions,
+    session: Option<HislipSession>,
+    srq: Option<SrqDispatcher>,
+}
+
+impl HislipTransport {
+    pub fn new(host: String, sub_address: String, options: HislipOptions) -> Self {
+        Self { host, sub_address, options, session: None, srq: None }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Sub-address of instrument, e.g. `hislip0`
+    pub fn sub_address(&self) -> &str {
+        &self.sub_address
+    }
+
+    pub fn options(&self) -> &HislipOptions {
+        &self.options
+    }
+
+    /// Negotiated protocol version as `(major, minor)`
+    pub fn version(&self) -> Option<(u8, u8)> {
+        self.session.as_ref().map(|s| s.version)
+    }
+
+    /// Server operates in overlapped mode
+    pub fn overlapped(&self) -> Option<bool> {
+        self.session.as_ref().map(|s| s.overlapped)
+    }
+
+    pub fn session_id(&self) -> Option<u16> {
+        self.session.as_ref().map(|s| s.session_id)
+    }
+
+    fn session(&mut self) -> io::Result<&mut HislipSession> {
+        self.session.as_mut().ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+
+    fn open_stream(&self) -> io::Result<TcpStream> {
+        let addr = (self.host.as_str(), self.options.port);
+        let stream = match self.options.connect_timeout {
+            Some(to) => {
+                let addr = addr.to_socket_addrs()?.next()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                TcpStream::connect_timeout(&addr, to)?
+            },
+            None => TcpStream::connect(addr)?,
+        };
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(self.options.connect_timeout)?;
+        stream.set_write_timeout(self.options.write_timeout)?;
+        Ok(stream)
+    }
+
+    fn open(&self) -> io::Result<HislipSession> {
+        let mut sync = self.open_stream()?;
+        let (major, minor) = self.options.version;
+        let param = u32::from_be_bytes([major, minor, VENDOR_ID[0], VENDOR_ID[1]]);
+        write_message(&mut sync, INITIALIZE, 0, param, self.sub_address.as_bytes())?;
+        let header = Header::read_from(&mut sync)?;
+        let payload = header.read_payload(&mut sync)?;
+        match header.kind {
+            INITIALIZE_RESPONSE => (),
+            FATAL_ERROR | ERROR => return Err(server_error(&header, &payload)),
+            _ => return Err(unexpected(&header)),
+        }
+        let [server_major, server_minor, id_hi, id_lo] = header.param.to_be_bytes();
+        let version = min((major, minor), (server_major, server_minor));
+        let session_id = u16::from_be_bytes([id_hi, id_lo]);
+        sync.set_read_timeout(self.options.read_timeout)?;
+
+        let asyn = self.open_stream()?;
+        let mut session = HislipSession {
+            sync, asyn, session_id, version,
+            overlapped: header.control & 1 != 0,
+            max_message_size: u64::MAX,
+            message_id: INITIAL_MESSAGE_ID,
+            rmt_delivered: false,
+            remaining: 0,
+            end: false,
+            service_requests: VecDeque::new(),
+            listener: None,
+        };
+        let to = self.options.connect_timeout;
+        session.exchange(ASYNC_INITIALIZE, 0, session_id as u32, &[], ASYNC_INITIALIZE_RESPONSE, to)?;
+        let size = self.options.max_message_size.to_be_bytes();
+        let (_, payload) = session.exchange(ASYNC_MAXIMUM_MESSAGE_SIZE, 0, 0, &size, ASYNC_MAXIMUM_MESSAGE_SIZE_RESPONSE, to)?;
+        if payload.len() == 8 {
+            let mut size = [0; 8];
+            size.copy_from_slice(&payload);
+            session.max_message_size = u64::from_be_bytes(size);
+        }
+        if let Some(ref dispatcher) = self.srq {
+            session.start_listener(dispatcher.clone())?;
+        }
+        Ok(session)
+    }
+
+    /// Clear instrument input and output buffers with `AsyncDeviceClear`
+    ///
+    /// Response data not yet read from transport is discarded.
+    pub fn device_clear(&mut self) -> KsResult<()> {
+        let timeout = self.options.read_timeout;
+        let session = self.session()?;
+        let (ack, _) = session.exchange(ASYNC_DEVICE_CLEAR, 0, 0, &[], ASYNC_DEVICE_CLEAR_ACKNOWLEDGE, timeout)?;
+        session.skip_remaining()?;
+        write_message(&mut session.sync, DEVICE_CLEAR_COMPLETE, ack.control, 0, &[])?;
+        loop {
+            let header = Header::read_from(&mut session.sync)?;
+            match header.kind {
+                DEVICE_CLEAR_ACKNOWLEDGE => break,
+                // Responses sent before clear are dropped
+                DATA | DATA_END => {
+                    session.remaining = header.len;
+                    session.skip_remaining()?;
+                },
+                _ => { header.read_payload(&mut session.sync)?; },
+            }
+        }
+        session.message_id = INITIAL_MESSAGE_ID;
+        session.rmt_delivered = false;
+        session.end = false;
+        Ok(())
+    }
+
+    /// Read status byte with `AsyncStatusQuery`
+    pub fn status_query(&mut self) -> KsResult<u8> {
+        let timeout = self.options.read_timeout;
+        let session = self.session()?;
+        let control = if session.rmt_delivered { RMT_DELIVERED } else { 0 };
+        session.rmt_delivered = false;
+        let param = session.message_id.wrapping_sub(2);
+        let (header, _) = session.exchange(ASYNC_STATUS_QUERY, control, param, &[], ASYNC_STATUS_RESPONSE, timeout)?;
+        Ok(header.control)
+    }
+
+    /// Request lock waiting for it up to `timeout`, returns `false` if lock was not granted in time
+    ///
+    /// Exclusive lock is requested if `shared` is `None`,
+    /// otherwise shared lock with given name is requested.
+    pub fn lock(&mut self, timeout: Duration, shared: Option<&str>) -> KsResult<bool> {
+        let reply_timeout = self.options.read_timeout.map(|to| to + timeout);
+        let session = self.session()?;
+        let name = shared.unwrap_or("").as_bytes();
+        let (header, _) = session.exchange(ASYNC_LOCK, LOCK_REQUEST, millis(timeout), name, ASYNC_LOCK_RESPONSE, reply_timeout)?;
+        match header.control {
+            LOCK_SUCCESS => Ok(true),
+            LOCK_FAILURE => Ok(false),
+            LOCK_ERROR => Err(KsError::Io(io::Error::new(io::ErrorKind::Other, "hislip: lock request error"))),
+            _ => Err(KsError::Io(unexpected_control(&header))),
+        }
+    }
+
+    /// Release lock held by this client
+    pub fn unlock(&mut self) -> KsResult<()> {
+        let timeout = self.options.read_timeout;
+        let session = self.session()?;
+        let param = session.message_id.wrapping_sub(2);
+        let (header, _) = session.exchange(ASYNC_LOCK, LOCK_RELEASE, param, &[], ASYNC_LOCK_RESPONSE, timeout)?;
+        match header.control {
+            LOCK_SUCCESS | LOCK_SUCCESS_SHARED => Ok(()),
+            LOCK_ERROR => Err(KsError::Io(io::Error::new(io::ErrorKind::Other, "hislip: no lock held"))),
+            _ => Err(KsError::Io(unexpected_control(&header))),
+        }
+    }
+
+    /// Wait for `AsyncServiceRequest` and return status byte sent with it
+    ///
+    /// Returns `None` if no service request arrived within `timeout`.
+    pub fn wait_service_request(&mut self, timeout: Option<Duration>) -> KsResult<Option<u8>> {
+        let session = self.session()?;
+        if let Some(stb) = session.service_requests.pop_front() {
+            return Ok(Some(stb));
+        }
+        loop {
+            let (header, payload) = match session.read_async(timeout) {
+                Ok(msg) => msg,
+                Err(err) => match err.kind() {
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => break Ok(None),
+                    _ => break Err(err.into()),
+                },
+            };
+            match header.kind {
+                ASYNC_SERVICE_REQUEST => break Ok(Some(header.control)),
+                ERROR | FATAL_ERROR => break Err(server_error(&header, &payload).into()),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl Read for HislipTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let session = self.session()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while session.remaining == 0 {
+            let header = Header::read_from(&mut session.sync)?;
+            match header.kind {
+                DATA | DATA_END => {
+                    session.remaining = header.len;
+                    session.end = header.kind == DATA_END;
+                    if session.end && header.len == 0 {
+                        session.rmt_delivered = true;
+                    }
+                },
+                ERROR | FATAL_ERROR => {
+                    let payload = header.read_payload(&mut session.sync)?;
+                    return Err(server_error(&header, &payload));
+                },
+                _ => return Err(unexpected(&header)),
+            }
+        }
+        let num = min(buf.len() as u64, session.remaining) as usize;
+        let num = session.sync.read(&mut buf[..num])?;
+        if num == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        session.remaining -= num as u64;
+        if session.remaining == 0 && session.end {
+            session.rmt_delivered = true;
+        }
+        Ok(num)
+    }
+}
+
+impl Transport for HislipTransport {
+    fn connect(&mut self) -> KsResult<()> {
+        if self.is_connected() {
+            return Err(KsError::Io(io::ErrorKind::AlreadyExists.into()))
+        }
+        self.session = Some(self.open()?);
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> KsResult<()> {
+        match self.session.take() {
+            Some(session) => {
+                let _ = session.asyn.shutdown(Shutdown::Both);
+                let _ = session.sync.shutdown(Shutdown::Both);
+                Ok(())
+            },
+            None => Err(KsError::Disconnected { consumed: 0 }),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.session.is_some()
+    }
+
+    fn read_end(&self) -> Option<bool> {
+        Some(self.session.as_ref().is_some_and(|session| session.remaining == 0 && session.end))
+    }
+
+    fn send(&mut self, data: &[u8]) -> KsResult<()> {
+        let session = self.session()?;
+        let size = min(session.max_message_size, usize::MAX as u64).max(1) as usize;
+        let mut chunks = data.chunks(size).peekable();
+        while let Some(chunk) = chunks.next() {
+            let kind = if chunks.peek().is_none() { DATA_END } else { DATA };
+            let control = if session.rmt_delivered { RMT_DELIVERED } else { 0 };
+            session.rmt_delivered = false;
+            write_message(&mut session.sync, kind, control, session.message_id, chunk)?;
+            session.message_id = session.message_id.wrapping_add(2);
+        }
+        Ok(())
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> KsResult<()> {
+        self.options.read_timeout = timeout;
+        if let Some(ref session) = self.session {
+            session.sync.set_read_timeout(timeout)?;
+        }
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> Option<Duration> {
+        self.options.read_timeout
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> KsResult<()> {
+        self.options.write_timeout = timeout;
+        if let Some(ref session) = self.session {
+            session.sync.set_write_timeout(timeout)?;
+            session.asyn.set_write_timeout(timeout)?;
+        }
+        Ok(())
+    }
+
+    fn write_timeout(&self) -> Option<Duration> {
+        self.options.write_timeout
+    }
+
+    fn clear(&mut self) -> KsResult<bool> {
+        self.device_clear().map(|()| true)
+    }
+
+    fn wait_service_request(&mut self, timeout: Option<Duration>) -> KsResult<Option<u8>> {
+        HislipTransport::wait_service_request(self, timeout)
+    }
+
+    /// Service requests are read from asynchronous channel by background thread
+    fn listen_service_requests(&mut self, dispatcher: SrqDispatcher) -> KsResult<bool> {
+        if let Some(ref mut session) = self.session {
+            // Running listener keeps its dispatcher until reconnect
+            if session.listener.is_none() {
+                session.start_listener(dispatcher.clone())?;
+            }
+        }
+        self.srq = Some(dispatcher);
+        Ok(true)
+    }
+
+    fn lock(&mut self, timeout: Duration, shared: Option<&str>) -> KsResult<bool> {
+        HislipTransport::lock(self, timeout, shared)
+    }
+
+    fn unlock(&mut self) -> KsResult<()> {
+        HislipTransport::unlock(self)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_roundtrip() {
+        let header = Header::new(DATA_END, 1, INITIAL_MESSAGE_ID, 5);
+        let bytes = header.to_bytes();
+        assert_eq!(&bytes[..4], b"HS\x07\x01");
+        assert_eq!(Header::read_from(&mut &bytes[..]).unwrap(), header);
+        assert!(Header::read_from(&mut &b"XS\x07\x01\0\0\0\0\0\0\0\0\0\0\0\0"[..]).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use std::io::prelude::*;
+use std::time::{Duration};
+
+use crate::{KsError, KsResult, SrqDispatcher};
+
+mod tcp;
+pub(crate) mod rpc;
+pub(crate) mod vxi11;
+pub(crate) mod hislip;
+
+pub use tcp::{TcpTransport, TcpOptions, DEFAULT_CONTROL_TIMEOUT};
+pub use vxi11::{Vxi11Transport, Vxi11Options};
+pub use hislip::{HislipTransport, HislipOptions};
+
+
+/// Connection to instrument carrying SCPI messages
+///
+/// Responses are read through `Read` implementation as a plain byte stream,
+/// so that `KsHook` parsing works the same way over any transport.
+pub trait Transport: Read {
+    /// Establish connection to instrument
+    fn connect(&mut self) -> KsResult<()>;
+    /// Close connection to instrument
+    fn disconnect(&mut self) -> KsResult<()>;
+    fn is_connected(&self) -> bool;
+
+    /// Send complete program message including its terminator
+    fn send(&mut self, data: &[u8]) -> KsResult<()>;
+
+    /// Whether data returned by the last `read` ends with END message terminator
+    ///
+    /// Transports returning `Some` never return data past END by single `read`,
+    /// so indefinite blocks containing newline bytes are read completely.
+    /// `None` means that transport can't signal END, e.g. raw socket carries bytes only,
+    /// then the first newline terminates indefinite block.
+    fn read_end(&self) -> Option<bool> {
+        None
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> KsResult<()>;
+    fn read_timeout(&self) -> Option<Duration>;
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> KsResult<()>;
+    fn write_timeout(&self) -> Option<Duration>;
+
+    /// Send device clear over out-of-band channel if transport has one
+    ///
+    /// Returns `true` if no stale response data can be read after that,
+    /// otherwise the caller has to drain the input.
+    fn clear(&mut self) -> KsResult<bool> {
+        Ok(false)
+    }
+
+    /// Wait for service request delivered over out-of-band channel and return status byte
+    ///
+    /// Returns `None` if no request arrived within `timeout`,
+    /// transports without such channel fail with `KsError::Unsupported`.
+    fn wait_service_request(&mut self, _timeout: Option<Duration>) -> KsResult<Option<u8>> {
+        Err(KsError::Unsupported("service request"))
+    }
+
+    /// Deliver service requests to `dispatcher` as they arrive, also after reconnect
+    ///
+    /// Returns `false` if transport has no channel for them,
+    /// then they can only be found by polling status byte.
+    fn listen_service_requests(&mut self, _dispatcher: SrqDispatcher) -> KsResult<bool> {
+        Ok(false)
+    }
+
+    /// Request lock waiting for it up to `timeout`, returns `false` if lock was not granted in time
+    ///
+    /// Exclusive lock is requested if `shared` is `None`, otherwise shared lock with given name.
+    /// Transports without locking fail with `KsError::Unsupported`.
+    fn lock(&mut self, _timeout: Duration, _shared: Option<&str>) -> KsResult<bool> {
+        Err(KsError::Unsupported("locking"))
+    }
+
+    /// Release lock held by this client
+    fn unlock(&mut self) -> KsResult<()> {
+        Err(KsError::Unsupported("locking"))
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn connect(&mut self) -> KsResult<()> {
+        (**self).connect()
+    }
+    fn disconnect(&mut self) -> KsResult<()> {
+        (**self).disconnect()
+    }
+    fn is_connected(&self) -> bool {
+        (**self).is_connected()
+    }
+
+    fn send(&mut self, data: &[u8]) -> KsResult<()> {
+        (**self).send(data)
+    }
+
+    fn read_end(&self) -> Option<bool> {
+        (**self).read_end()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> KsResult<()> {
+        (**self).set_read_timeout(timeout)
+    }
+    fn read_timeout(&self) -> Option<Duration> {
+        (**self).read_timeout()
+    }
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> KsResult<()> {
+        (**self).set_write_timeout(timeout)
+    }
+    fn write_timeout(&self) -> Option<Duration> {
+        (**self).write_timeout()
+    }
+
+    fn clear(&mut self) -> KsResult<bool> {
+        (**self).clear()
+    }
+
+    fn wait_service_request(&mut self, timeout: Option<Duration>) -> KsResult<Option<u8>> {
+        (**self).wait_service_request(timeout)
+    }
+
+    fn listen_service_requests(&mut self, dispatcher: SrqDispatcher) -> KsResult<bool> {
+        (**self).listen_service_requests(dispatcher)
+    }
+
+    fn lock(&mut self, timeout: Duration, shared: Option<&str>) -> KsResult<bool> {
+        (**self).lock(timeout, shared)
+    }
+    fn unlock(&mut self) -> KsResult<()> {
+        (**self).unlock()
+    }
+}
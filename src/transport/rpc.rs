@@ -0,0 +1,285 @@
+//! Minimal ONC RPC (RFC 5531) over TCP with XDR encoding, just enough for VXI-11
+
+use std::io::prelude::*;
+use std::io::{self};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration};
+
+
+pub const CALL: u32 = 0;
+pub const REPLY: u32 = 1;
+pub const RPC_VERSION: u32 = 2;
+pub const MSG_ACCEPTED: u32 = 0;
+pub const SUCCESS: u32 = 0;
+pub const PROG_UNAVAIL: u32 = 1;
+pub const PROC_UNAVAIL: u32 = 3;
+pub const AUTH_NONE: u32 = 0;
+
+pub const PORTMAP_PROG: u32 = 100000;
+pub const PORTMAP_VERS: u32 = 2;
+pub const PORTMAP_GETPORT: u32 = 3;
+pub const IPPROTO_TCP: u32 = 6;
+
+/// Upper bound of a single record to protect from corrupted fragment headers
+const MAX_RECORD_SIZE: usize = 16 << 20;
+const LAST_FRAGMENT: u32 = 1 << 31;
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// XDR encoder
+#[derive(Default)]
+pub struct XdrWriter {
+    buf: Vec<u8>,
+}
+
+impl XdrWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u32(&mut self, x: u32) -> &mut Self {
+        self.buf.extend_from_slice(&x.to_be_bytes());
+        self
+    }
+
+    pub fn i32(&mut self, x: i32) -> &mut Self {
+        self.buf.extend_from_slice(&x.to_be_bytes());
+        self
+    }
+
+    pub fn bool(&mut self, x: bool) -> &mut Self {
+        self.u32(x as u32)
+    }
+
+    /// Variable-length opaque data padded to 4 bytes
+    pub fn opaque(&mut self, data: &[u8]) -> &mut Self {
+        self.u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+        let pad = (4 - data.len() % 4) % 4;
+        self.buf.extend_from_slice(&[0; 3][..pad]);
+        self
+    }
+
+    pub fn string(&mut self, text: &str) -> &mut Self {
+        self.opaque(text.as_bytes())
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// XDR decoder
+pub struct XdrReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> XdrReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid_data("xdr: unexpected end of data"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    pub fn opaque(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        let data = self.take(len)?;
+        self.take((4 - len % 4) % 4)?;
+        Ok(data)
+    }
+
+    /// Data that is not decoded yet
+    pub fn rest(&self) -> &'a [u8] {
+        self.buf
+    }
+}
+
+/// Write record as a single last fragment
+pub fn write_record<W: Write>(stream: &mut W, data: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(data.len() + 4);
+    buf.extend_from_slice(&(data.len() as u32 | LAST_FRAGMENT).to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf)
+    .and_then(|()| stream.flush())
+}
+
+/// Read record joining its fragments
+pub fn read_record<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut record = Vec::new();
+    loop {
+        let mut head = [0; 4];
+        stream.read_exact(&mut head)?;
+        let head = u32::from_be_bytes(head);
+        let len = (head & !LAST_FRAGMENT) as usize;
+        if record.len() + len > MAX_RECORD_SIZE {
+            return Err(invalid_data("rpc: record is too large"));
+        }
+        let start = record.len();
+        record.resize(start + len, 0);
+        stream.read_exact(&mut record[start..])?;
+        if head & LAST_FRAGMENT != 0 {
+            break Ok(record);
+        }
+    }
+}
+
+/// Call message header followed by encoded arguments
+pub fn call_message(xid: u32, prog: u32, vers: u32, procedure: u32, args: &[u8]) -> Vec<u8> {
+    let mut w = XdrWriter::new();
+    w.u32(xid).u32(CALL).u32(RPC_VERSION).u32(prog).u32(vers).u32(procedure)
+    .u32(AUTH_NONE).opaque(&[]).u32(AUTH_NONE).opaque(&[]);
+    let mut msg = w.into_inner();
+    msg.extend_from_slice(args);
+    msg
+}
+
+/// RPC call message received by server
+pub struct RpcCall {
+    pub xid: u32,
+    pub prog: u32,
+    pub vers: u32,
+    pub procedure: u32,
+    pub args: Vec<u8>,
+}
+
+impl RpcCall {
+    pub fn parse(record: &[u8]) -> io::Result<Self> {
+        let mut r = XdrReader::new(record);
+        let xid = r.u32()?;
+        if r.u32()? != CALL || r.u32()? != RPC_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "rpc: not a call message"));
+        }
+        let (prog, vers, procedure) = (r.u32()?, r.u32()?, r.u32()?);
+        // Credentials and verifier are ignored
+        for _ in 0..2 {
+            r.u32()?;
+            r.opaque()?;
+        }
+        Ok(Self { xid, prog, vers, procedure, args: r.rest().to_vec() })
+    }
+
+    /// Accepted reply with given status and results
+    pub fn reply(&self, accept_stat: u32, results: &[u8]) -> Vec<u8> {
+        let mut w = XdrWriter::new();
+        w.u32(self.xid).u32(REPLY).u32(MSG_ACCEPTED).u32(AUTH_NONE).opaque(&[]).u32(accept_stat);
+        let mut buf = w.into_inner();
+        buf.extend_from_slice(results);
+        buf
+    }
+}
+
+/// Client of a single RPC program over TCP connection
+pub struct RpcClient {
+    stream: TcpStream,
+    prog: u32,
+    vers: u32,
+    xid: u32,
+}
+
+impl RpcClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A, prog: u32, vers: u32, timeout: Option<Duration>) -> io::Result<Self> {
+        let stream = match timeout {
+            Some(to) => {
+                let addr = addr.to_socket_addrs()?.next()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                TcpStream::connect_timeout(&addr, to)?
+            },
+            None => TcpStream::connect(addr)?,
+        };
+        stream.set_nodelay(true)?;
+        Ok(Self { stream, prog, vers, xid: 0 })
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Call procedure and return its encoded results
+    pub fn call(&mut self, procedure: u32, args: &[u8]) -> io::Result<Vec<u8>> {
+        self.xid = self.xid.wrapping_add(1);
+        let msg = call_message(self.xid, self.prog, self.vers, procedure, args);
+        write_record(&mut self.stream, &msg)?;
+
+        loop {
+            let record = read_record(&mut self.stream)?;
+            let mut r = XdrReader::new(&record);
+            // Replies to timed out calls may arrive late, they are skipped
+            if r.u32()? != self.xid {
+                continue;
+            }
+            if r.u32()? != REPLY {
+                return Err(invalid_data("rpc: not a reply message"));
+            }
+            if r.u32()? != MSG_ACCEPTED {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "rpc: call denied"));
+            }
+            r.u32()?;
+            r.opaque()?;
+            return match r.u32()? {
+                SUCCESS => Ok(r.rest().to_vec()),
+                PROG_UNAVAIL => Err(io::Error::new(io::ErrorKind::NotFound, "rpc: program unavailable")),
+                PROC_UNAVAIL => Err(io::Error::new(io::ErrorKind::Unsupported, "rpc: procedure unavailable")),
+                _ => Err(invalid_data("rpc: call failed")),
+            };
+        }
+    }
+}
+
+/// Ask portmapper for TCP port of RPC program
+pub fn getport(host: &str, pm_port: u16, prog: u32, vers: u32, timeout: Option<Duration>) -> io::Result<u16> {
+    let mut pm = RpcClient::connect((host, pm_port), PORTMAP_PROG, PORTMAP_VERS, timeout)?;
+    pm.stream.set_read_timeout(timeout)?;
+    let mut w = XdrWriter::new();
+    w.u32(prog).u32(vers).u32(IPPROTO_TCP).u32(0);
+    let res = pm.call(PORTMAP_GETPORT, &w.into_inner())?;
+    match XdrReader::new(&res).u32()? {
+        0 => Err(io::Error::new(io::ErrorKind::NotFound, "portmap: program is not registered")),
+        port => Ok(port as u16),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xdr_roundtrip() {
+        let mut w = XdrWriter::new();
+        w.u32(1).i32(-2).bool(true).opaque(b"abcde").string("inst0");
+        let buf = w.into_inner();
+        assert_eq!(buf.len(), 4 * 3 + 4 + 8 + 4 + 8);
+
+        let mut r = XdrReader::new(&buf);
+        assert_eq!(r.u32().unwrap(), 1);
+        assert_eq!(r.u32().unwrap(), -2i32 as u32);
+        assert_eq!(r.u32().unwrap(), 1);
+        assert_eq!(r.opaque().unwrap(), b"abcde");
+        assert_eq!(r.opaque().unwrap(), b"inst0");
+        assert!(r.u32().is_err());
+    }
+
+    #[test]
+    fn fragmented_record() {
+        let stream = [
+            0, 0, 0, 2, b'a', b'b',
+            0x80, 0, 0, 1, b'c',
+        ];
+        assert_eq!(read_record(&mut &stream[..]).unwrap(), b"abc");
+    }
+}
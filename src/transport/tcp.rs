@@ -0,0 +1,303 @@
+use std::convert::TryFrom;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{TcpStream, ToSocketAddrs, Shutdown};
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration};
+
+use socket2::{SockRef, TcpKeepalive};
+
+use crate::{KsError, KsResult, KsValue, StatusByte, SrqDispatcher, DEFAULT_POLL_INTERVAL};
+use super::{Transport};
+
+
+/// Timeout of control connection used when transport has no read timeout
+pub const DEFAULT_CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Options of raw TCP socket connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// Timeout of establishing connection
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    /// Disable Nagle algorithm, `TCP_NODELAY`
+    pub nodelay: bool,
+    /// Enable keepalive probes after given idle time
+    pub keepalive: Option<Duration>,
+    /// Size of socket receive buffer, `SO_RCVBUF`
+    pub recv_buffer_size: Option<usize>,
+    /// Size of socket send buffer, `SO_SNDBUF`
+    pub send_buffer_size: Option<usize>,
+    /// Port of Keysight socket control connection used for device clear,
+    /// instrument reports it with `SYSTem:COMMunicate:TCPip:CONTrol?`
+    pub control_port: Option<u16>,
+    /// Interval of polling status byte for service requests, [`DEFAULT_POLL_INTERVAL`] if not set
+    pub srq_poll_interval: Option<Duration>,
+}
+
+impl TcpOptions {
+    /// Use the same timeout for connecting, reading and writing
+    pub fn with_timeout(timeout: Option<Duration>) -> Self {
+        Self {
+            connect_timeout: timeout,
+            read_timeout: timeout,
+            write_timeout: timeout,
+            ..Self::default()
+        }
+    }
+}
+
+/// Thread polling status byte over its own connection, the connection is shut down on drop
+struct SrqPoller {
+    stop: Arc<AtomicBool>,
+    stream: TcpStream,
+}
+
+impl SrqPoller {
+    fn start(stream: TcpStream, interval: Duration, dispatcher: SrqDispatcher) -> io::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (flag, conn) = (stop.clone(), stream.try_clone()?);
+        thread::spawn(move || {
+            // Polling just stops when connection fails
+            let _ = poll_status(conn, interval, &flag, &dispatcher);
+        });
+        Ok(Self { stop, stream })
+    }
+}
+
+impl Drop for SrqPoller {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Query `*STB?` every `interval` and dispatch status byte each time `MSS` bit gets set
+fn poll_status(stream: TcpStream, interval: Duration, stop: &AtomicBool, dispatcher: &SrqDispatcher) -> KsResult<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut mss = false;
+    while !stop.load(Ordering::SeqCst) {
+        writer.write_all(b"*STB?\n")?;
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(KsError::Disconnected { consumed: 0 });
+        }
+        let value = KsValue::parse(&line)?;
+        let stb = value.as_i64()
+        .and_then(|v| u8::try_from(v).ok())
+        .map(StatusByte::from_bits_retain)
+        .ok_or_else(|| KsError::bad_response(format!("status byte {:?}", line)))?;
+        if stb.contains(StatusByte::MSS) && !mss {
+            dispatcher.dispatch(stb);
+        }
+        mss = stb.contains(StatusByte::MSS);
+        thread::sleep(interval);
+    }
+    Ok(())
+}
+
+/// Raw SCPI socket transport, usually port 5025 of Keysight instruments
+///
+/// Socket carries no END indication, so indefinite block response
+/// is terminated by the first newline byte in its content.
+/// Service requests are found by polling status byte over separate connection to the same port,
+/// it is opened only when they are listened for.
+pub struct TcpTransport {
+    addr: (String, u16),
+    options: TcpOptions,
+    stream: Option<TcpStream>,
+    control: Option<BufReader<TcpStream>>,
+    srq: Option<SrqDispatcher>,
+    poller: Option<SrqPoller>,
+}
+
+impl TcpTransport {
+    pub fn new(addr: (String, u16), options: TcpOptions) -> Self {
+        Self { addr, options, stream: None, control: None, srq: None, poller: None }
+    }
+
+    pub fn address(&self) -> (&str, u16) {
+        (self.addr.0.as_str(), self.addr.1)
+    }
+
+    pub fn options(&self) -> &TcpOptions {
+        &self.options
+    }
+
+    /// Change options, socket-level options are applied on the next connect
+    pub fn set_options(&mut self, options: TcpOptions) -> KsResult<()> {
+        self.options = options;
+        self.control = None;
+        match self.stream {
+            Some(ref stream) => {
+                stream.set_read_timeout(self.options.read_timeout)?;
+                stream.set_write_timeout(self.options.write_timeout)?;
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn connect_to(&self, port: u16, timeout: Option<Duration>) -> io::Result<TcpStream> {
+        let addr = (self.addr.0.as_str(), port);
+        match timeout {
+            Some(to) => {
+                let mut last = io::Error::from(io::ErrorKind::NotFound);
+                let mut stream = None;
+                for addr in addr.to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&addr, to) {
+                        Ok(s) => { stream = Some(s); break; },
+                        Err(e) => last = e,
+                    }
+                }
+                stream.ok_or(last)
+            },
+            None => TcpStream::connect(addr),
+        }
+    }
+
+    fn open(&self) -> io::Result<TcpStream> {
+        let stream = self.connect_to(self.addr.1, self.options.connect_timeout)?;
+        stream.set_read_timeout(self.options.read_timeout)?;
+        stream.set_write_timeout(self.options.write_timeout)?;
+        stream.set_nodelay(self.options.nodelay)?;
+
+        let sock = SockRef::from(&stream);
+        if let Some(time) = self.options.keepalive {
+            sock.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        if let Some(size) = self.options.recv_buffer_size {
+            sock.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.options.send_buffer_size {
+            sock.set_send_buffer_size(size)?;
+        }
+
+        Ok(stream)
+    }
+
+    /// Start polling status byte if service requests are listened for
+    fn start_poller(&mut self) -> io::Result<()> {
+        if let Some(ref dispatcher) = self.srq {
+            let stream = self.connect_to(self.addr.1, self.options.connect_timeout)?;
+            stream.set_read_timeout(self.options.read_timeout)?;
+            stream.set_write_timeout(self.options.write_timeout)?;
+            let interval = self.options.srq_poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL);
+            self.poller = Some(SrqPoller::start(stream, interval, dispatcher.clone())?);
+        }
+        Ok(())
+    }
+
+    fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        self.stream.as_mut().ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+
+    /// Send `DCL` over control connection and wait for instrument to echo it back
+    ///
+    /// Control connection uses the current read timeout of transport
+    /// or [`DEFAULT_CONTROL_TIMEOUT`], so that dead control port never blocks forever.
+    fn device_clear(&mut self, port: u16) -> KsResult<()> {
+        let timeout = Some(self.options.read_timeout.unwrap_or(DEFAULT_CONTROL_TIMEOUT));
+        let mut control = match self.control.take() {
+            Some(control) => control,
+            None => BufReader::new(self.connect_to(port, self.options.connect_timeout.or(timeout))?),
+        };
+        control.get_ref().set_read_timeout(timeout)?;
+        control.get_ref().set_write_timeout(timeout)?;
+        control.get_mut().write_all(b"DCL\n")?;
+        let mut line = String::new();
+        control.read_line(&mut line)?;
+        if line.trim_end() != "DCL" {
+            return Err(KsError::bad_response(format!("control connection: {:?}", line)));
+        }
+        self.control = Some(control);
+        Ok(())
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream()?.read(buf)
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect(&mut self) -> KsResult<()> {
+        if self.is_connected() {
+            return Err(KsError::Io(io::ErrorKind::AlreadyExists.into()))
+        }
+        self.stream = Some(self.open()?);
+        self.start_poller()?;
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> KsResult<()> {
+        match self.stream.take() {
+            // Peer may have already closed the connection, so the result is ignored
+            Some(stream) => {
+                self.control = None;
+                self.poller = None;
+                let _ = stream.shutdown(Shutdown::Both);
+                Ok(())
+            },
+            None => Err(KsError::Disconnected { consumed: 0 }),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn send(&mut self, data: &[u8]) -> KsResult<()> {
+        let stream = self.stream()?;
+        stream.write_all(data)?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> KsResult<()> {
+        self.options.read_timeout = timeout;
+        if let Some(ref stream) = self.stream {
+            stream.set_read_timeout(timeout)?;
+        }
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> Option<Duration> {
+        self.options.read_timeout
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> KsResult<()> {
+        self.options.write_timeout = timeout;
+        if let Some(ref stream) = self.stream {
+            stream.set_write_timeout(timeout)?;
+        }
+        Ok(())
+    }
+
+    fn write_timeout(&self) -> Option<Duration> {
+        self.options.write_timeout
+    }
+
+    /// Device clear is sent if `control_port` is set,
+    /// data already sent by instrument still has to be drained
+    fn clear(&mut self) -> KsResult<bool> {
+        if let Some(port) = self.options.control_port {
+            self.device_clear(port)?;
+        }
+        Ok(false)
+    }
+
+    /// Status byte is polled over separate connection, so instrument has to accept two of them
+    fn listen_service_requests(&mut self, dispatcher: SrqDispatcher) -> KsResult<bool> {
+        self.srq = Some(dispatcher);
+        if self.is_connected() && self.poller.is_none() {
+            self.start_poller()?;
+        }
+        Ok(true)
+    }
+}
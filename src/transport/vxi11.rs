@@ -0,0 +1,415 @@
+use std::io::prelude::*;
+use std::io::{self};
+use std::cmp::min;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration};
+
+use crate::{KsError, KsResult, StatusByte, SrqDispatcher};
+use super::{Transport};
+use super::rpc::{self, RpcCall, RpcClient, XdrWriter, XdrReader};
+
+
+pub const DEVICE_CORE: u32 = 0x0607AF;
+pub const DEVICE_CORE_VERSION: u32 = 1;
+pub const DEVICE_INTR: u32 = 0x0607B1;
+pub const DEVICE_INTR_VERSION: u32 = 1;
+
+pub const CREATE_LINK: u32 = 10;
+pub const DEVICE_WRITE: u32 = 11;
+pub const DEVICE_READ: u32 = 12;
+pub const DEVICE_CLEAR: u32 = 15;
+pub const DEVICE_LOCK: u32 = 18;
+pub const DEVICE_UNLOCK: u32 = 19;
+pub const DEVICE_ENABLE_SRQ: u32 = 20;
+pub const DESTROY_LINK: u32 = 23;
+pub const CREATE_INTR_CHAN: u32 = 25;
+pub const DESTROY_INTR_CHAN: u32 = 26;
+pub const DEVICE_INTR_SRQ: u32 = 30;
+
+/// Interrupt channel family: TCP
+pub const DEVICE_TCP: u32 = 0;
+/// Handle passed back by instrument with each service request
+const SRQ_HANDLE: &[u8] = b"ks-lxi";
+
+/// Device flag to wait for lock held by another link
+pub const FLAG_WAITLOCK: u32 = 1;
+/// Device flag marking the last chunk of message
+pub const FLAG_END: u32 = 8;
+/// Read reason: END indicator received
+pub const REASON_END: u32 = 4;
+
+pub const ERR_CHANNEL_NOT_ESTABLISHED: u32 = 6;
+pub const ERR_DEVICE_LOCKED: u32 = 11;
+pub const ERR_NO_LOCK_HELD: u32 = 12;
+pub const ERR_IO_TIMEOUT: u32 = 15;
+
+/// Default port of portmapper
+pub const PORTMAPPER_PORT: u16 = 111;
+
+/// Extra time given to RPC reply after instrument-side I/O timeout expires
+const REPLY_MARGIN: Duration = Duration::from_secs(1);
+
+fn device_error(code: u32) -> io::Error {
+    let msg = match code {
+        1 => "syntax error",
+        3 => "device not accessible",
+        4 => "invalid link identifier",
+        5 => "parameter error",
+        ERR_CHANNEL_NOT_ESTABLISHED => "channel not established",
+        8 => "operation not supported",
+        9 => "out of resources",
+        ERR_DEVICE_LOCKED => "device locked by another link",
+        ERR_NO_LOCK_HELD => "no lock held by this link",
+        ERR_IO_TIMEOUT => return io::ErrorKind::TimedOut.into(),
+        17 => "I/O error",
+        21 => "invalid address",
+        23 => "abort",
+        29 => "channel already established",
+        _ => "unknown error",
+    };
+    io::Error::new(io::ErrorKind::Other, format!("vxi11: error {}, {}", code, msg))
+}
+
+fn check_error(code: u32) -> io::Result<()> {
+    match code {
+        0 => Ok(()),
+        code => Err(device_error(code)),
+    }
+}
+
+fn millis(timeout: Option<Duration>) -> u32 {
+    timeout.map_or(u32::MAX, |to| min(to.as_millis(), u32::MAX as u128) as u32)
+}
+
+/// Options of VXI-11 connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vxi11Options {
+    /// Port of portmapper used to find core channel
+    pub portmapper_port: u16,
+    pub connect_timeout: Option<Duration>,
+    /// Instrument-side I/O timeout of `device_read`
+    pub read_timeout: Option<Duration>,
+    /// Instrument-side I/O timeout of `device_write`
+    pub write_timeout: Option<Duration>,
+}
+
+impl Default for Vxi11Options {
+    fn default() -> Self {
+        Self {
+            portmapper_port: PORTMAPPER_PORT,
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+}
+
+/// Server of interrupt channel, instrument connects to it to call `device_intr_srq`
+struct IntrChannel {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    /// Connection being served, it is shut down on drop to unblock the thread
+    conn: Arc<Mutex<Option<TcpStream>>>,
+}
+
+impl IntrChannel {
+    fn start(ip: IpAddr, dispatcher: SrqDispatcher) -> io::Result<Self> {
+        let listener = TcpListener::bind((ip, 0))?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let conn = Arc::new(Mutex::new(None));
+        let (flag, current) = (stop.clone(), conn.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                *current.lock().unwrap() = stream.try_clone().ok();
+                // Checked after storing connection, so drop either sees it or stop is seen here
+                if flag.load(Ordering::SeqCst) {
+                    break;
+                }
+                let _ = serve_intr(stream, &dispatcher);
+                current.lock().unwrap().take();
+            }
+        });
+        Ok(Self { addr, stop, conn })
+    }
+}
+
+impl Drop for IntrChannel {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(stream) = self.conn.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        // Wake listener thread waiting for connection
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+/// Serve interrupt channel connection until instrument closes it
+///
+/// `device_intr_srq` carries no status byte, so only `MSS` bit is reported.
+fn serve_intr(mut stream: TcpStream, dispatcher: &SrqDispatcher) -> io::Result<()> {
+    loop {
+        let call = RpcCall::parse(&rpc::read_record(&mut stream)?)?;
+        let reply = if call.prog != DEVICE_INTR || call.vers != DEVICE_INTR_VERSION {
+            call.reply(rpc::PROG_UNAVAIL, &[])
+        } else if call.procedure == DEVICE_INTR_SRQ {
+            if XdrReader::new(&call.args).opaque()? == SRQ_HANDLE {
+                dispatcher.dispatch(StatusByte::MSS);
+            }
+            call.reply(rpc::SUCCESS, &[])
+        } else {
+            call.reply(rpc::PROC_UNAVAIL, &[])
+        };
+        rpc::write_record(&mut stream, &reply)?;
+    }
+}
+
+struct Vxi11Link {
+    rpc: RpcClient,
+    lid: u32,
+    max_recv_size: usize,
+    /// Data returned by `device_read` that didn't fit into caller buffer
+    rest: Vec<u8>,
+    /// Last `device_read` ended with END
+    end: bool,
+    intr: Option<IntrChannel>,
+}
+
+/// VXI-11 core channel transport for instruments without raw SCPI socket
+pub struct Vxi11Transport {
+    host: String,
+    device: String,
+    options: Vxi11Options,
+    link: Option<Vxi11Link>,
+    srq: Option<SrqDispatcher>,
+}
+
+impl Vxi11Transport {
+    pub fn new(host: String, device: String, options: Vxi11Options) -> Self {
+        Self { host, device, options, link: None, srq: None }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Logical device name, e.g. `inst0`
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    pub fn options(&self) -> &Vxi11Options {
+        &self.options
+    }
+
+    fn link(&mut self) -> io::Result<&mut Vxi11Link> {
+        self.link.as_mut().ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+
+    fn set_reply_timeout(link: &Vxi11Link, io_timeout: Option<Duration>) -> io::Result<()> {
+        link.rpc.stream().set_read_timeout(io_timeout.map(|to| to + REPLY_MARGIN))
+    }
+
+    fn create_link(&self) -> io::Result<Vxi11Link> {
+        let to = self.options.connect_timeout;
+        let port = rpc::getport(&self.host, self.options.portmapper_port, DEVICE_CORE, DEVICE_CORE_VERSION, to)?;
+        let mut rpc = RpcClient::connect((self.host.as_str(), port), DEVICE_CORE, DEVICE_CORE_VERSION, to)?;
+        rpc.stream().set_read_timeout(to)?;
+
+        let mut w = XdrWriter::new();
+        w.i32(std::process::id() as i32).bool(false).u32(0).string(&self.device);
+        let res = rpc.call(CREATE_LINK, &w.into_inner())?;
+        let mut r = XdrReader::new(&res);
+        check_error(r.u32()?)?;
+        let lid = r.u32()?;
+        let _abort_port = r.u32()?;
+        let max_recv_size = r.u32()? as usize;
+
+        let mut link = Vxi11Link { rpc, lid, max_recv_size: max_recv_size.max(1), rest: Vec::new(), end: false, intr: None };
+        if let Some(ref dispatcher) = self.srq {
+            Self::enable_srq(&mut link, dispatcher.clone())?;
+        }
+        Ok(link)
+    }
+
+    /// Create interrupt channel and enable service requests on it
+    fn enable_srq(link: &mut Vxi11Link, dispatcher: SrqDispatcher) -> io::Result<()> {
+        let local = link.rpc.stream().local_addr()?;
+        let ip = match local.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "vxi11: interrupt channel requires IPv4"));
+            },
+        };
+        let intr = IntrChannel::start(local.ip(), dispatcher)?;
+        let mut w = XdrWriter::new();
+        w.u32(u32::from(ip)).u32(intr.addr.port() as u32).u32(DEVICE_INTR).u32(DEVICE_INTR_VERSION).u32(DEVICE_TCP);
+        let res = link.rpc.call(CREATE_INTR_CHAN, &w.into_inner())?;
+        check_error(XdrReader::new(&res).u32()?)?;
+        link.intr = Some(intr);
+
+        let mut w = XdrWriter::new();
+        w.u32(link.lid).bool(true).opaque(SRQ_HANDLE);
+        let res = link.rpc.call(DEVICE_ENABLE_SRQ, &w.into_inner())?;
+        check_error(XdrReader::new(&res).u32()?)
+    }
+
+    /// Clear instrument input and output buffers with `device_clear`
+    pub fn device_clear(&mut self) -> KsResult<()> {
+        let io_timeout = self.options.read_timeout;
+        let link = self.link()?;
+        Self::set_reply_timeout(link, io_timeout)?;
+        let mut w = XdrWriter::new();
+        w.u32(link.lid).u32(0).u32(0).u32(millis(io_timeout));
+        let res = link.rpc.call(DEVICE_CLEAR, &w.into_inner())?;
+        check_error(XdrReader::new(&res).u32()?)?;
+        link.rest.clear();
+        link.end = false;
+        Ok(())
+    }
+}
+
+impl Read for Vxi11Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let io_timeout = self.options.read_timeout;
+        let link = self.link()?;
+        if link.rest.is_empty() {
+            Self::set_reply_timeout(link, io_timeout)?;
+            let mut w = XdrWriter::new();
+            w.u32(link.lid).u32(buf.len() as u32).u32(millis(io_timeout)).u32(0).u32(0).u32(0);
+            let res = link.rpc.call(DEVICE_READ, &w.into_inner())?;
+            let mut r = XdrReader::new(&res);
+            check_error(r.u32()?)?;
+            link.end = r.u32()? & REASON_END != 0;
+            link.rest = r.opaque()?.to_vec();
+        }
+        let num = min(buf.len(), link.rest.len());
+        buf[..num].copy_from_slice(&link.rest[..num]);
+        link.rest.drain(..num);
+        Ok(num)
+    }
+}
+
+impl Transport for Vxi11Transport {
+    fn connect(&mut self) -> KsResult<()> {
+        if self.is_connected() {
+            return Err(KsError::Io(io::ErrorKind::AlreadyExists.into()))
+        }
+        self.link = Some(self.create_link()?);
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> KsResult<()> {
+        match self.link.take() {
+            Some(mut link) => {
+                // Link is dropped by instrument on connection close anyway
+                if link.intr.is_some() {
+                    let _ = link.rpc.call(DESTROY_INTR_CHAN, &[]);
+                }
+                let mut w = XdrWriter::new();
+                w.u32(link.lid);
+                let _ = link.rpc.call(DESTROY_LINK, &w.into_inner());
+                let _ = link.rpc.stream().shutdown(Shutdown::Both);
+                Ok(())
+            },
+            None => Err(KsError::Disconnected { consumed: 0 }),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.link.is_some()
+    }
+
+    fn read_end(&self) -> Option<bool> {
+        Some(self.link.as_ref().is_some_and(|link| link.rest.is_empty() && link.end))
+    }
+
+    fn send(&mut self, data: &[u8]) -> KsResult<()> {
+        let io_timeout = self.options.write_timeout;
+        let link = self.link()?;
+        Self::set_reply_timeout(link, io_timeout)?;
+        let mut chunks = data.chunks(link.max_recv_size).peekable();
+        while let Some(chunk) = chunks.next() {
+            let flags = if chunks.peek().is_none() { FLAG_END } else { 0 };
+            let mut w = XdrWriter::new();
+            w.u32(link.lid).u32(millis(io_timeout)).u32(0).u32(flags).opaque(chunk);
+            let res = link.rpc.call(DEVICE_WRITE, &w.into_inner())?;
+            let mut r = XdrReader::new(&res);
+            check_error(r.u32()?)?;
+            if r.u32()? as usize != chunk.len() {
+                return Err(KsError::Io(io::Error::new(io::ErrorKind::Other, "vxi11: incomplete device_write")));
+            }
+        }
+        Ok(())
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> KsResult<()> {
+        self.options.read_timeout = timeout;
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> Option<Duration> {
+        self.options.read_timeout
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> KsResult<()> {
+        self.options.write_timeout = timeout;
+        Ok(())
+    }
+
+    fn write_timeout(&self) -> Option<Duration> {
+        self.options.write_timeout
+    }
+
+    /// Responses are only delivered on `device_read` requests, so nothing stale is left after clear
+    fn clear(&mut self) -> KsResult<bool> {
+        self.device_clear().map(|()| true)
+    }
+
+    /// Only exclusive lock is supported by `device_lock`
+    fn lock(&mut self, timeout: Duration, shared: Option<&str>) -> KsResult<bool> {
+        if shared.is_some() {
+            return Err(KsError::Unsupported("shared lock"));
+        }
+        let link = self.link()?;
+        Self::set_reply_timeout(link, Some(timeout))?;
+        let mut w = XdrWriter::new();
+        w.u32(link.lid).u32(FLAG_WAITLOCK).u32(millis(Some(timeout)));
+        let res = link.rpc.call(DEVICE_LOCK, &w.into_inner())?;
+        match XdrReader::new(&res).u32()? {
+            ERR_DEVICE_LOCKED => Ok(false),
+            code => check_error(code).map(|()| true).map_err(KsError::from),
+        }
+    }
+
+    fn unlock(&mut self) -> KsResult<()> {
+        let io_timeout = self.options.read_timeout;
+        let link = self.link()?;
+        Self::set_reply_timeout(link, io_timeout)?;
+        let mut w = XdrWriter::new();
+        w.u32(link.lid);
+        let res = link.rpc.call(DEVICE_UNLOCK, &w.into_inner())?;
+        check_error(XdrReader::new(&res).u32()?).map_err(KsError::from)
+    }
+
+    /// Service requests arrive over interrupt channel, it is created on each connect
+    fn listen_service_requests(&mut self, dispatcher: SrqDispatcher) -> KsResult<bool> {
+        let io_timeout = self.options.read_timeout;
+        if let Some(ref mut link) = self.link {
+            if link.intr.is_none() {
+                Self::set_reply_timeout(link, io_timeout)?;
+                Self::enable_srq(link, dispatcher.clone())?;
+            }
+        }
+        self.srq = Some(dispatcher);
+        Ok(true)
+    }
+}
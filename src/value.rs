@@ -0,0 +1,340 @@
+use std::ops::Index;
+use std::str::{self, FromStr};
+
+use crate::{KsError, KsResult};
+
+
+fn invalid_data(msg: &'static str) -> KsError {
+    KsError::bad_response(msg)
+}
+
+/// Single IEEE 488.2 response data element
+#[derive(Debug, Clone, PartialEq)]
+pub enum KsValue {
+    /// Integer (NR1) numeric response data, e.g. `+123`
+    Integer(i64),
+    /// Decimal (NR2 or NR3) numeric response data, e.g. `+1.23456789E+00`
+    Decimal(f64),
+    /// Character response data, e.g. `VOLT` or `ON`
+    Character(String),
+    /// String response data with surrounding quotes removed and doubled quotes unescaped
+    String(String),
+    /// Arbitrary block response data embedded into message
+    Block(Vec<u8>),
+}
+
+impl KsValue {
+    /// Parse single response data element
+    pub fn parse(text: &str) -> KsResult<Self> {
+        let text = text.trim_matches(|c: char| c.is_ascii_whitespace());
+        match text.chars().next() {
+            None => Err(invalid_data("value parse: empty response data")),
+            Some(q @ '"') | Some(q @ '\'') => parse_string(text, q).map(KsValue::String),
+            Some(c) if c.is_ascii_digit() || c == '+' || c == '-' || c == '.' => parse_numeric(text),
+            Some(c) if c.is_ascii_alphabetic() => {
+                if text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    Ok(KsValue::Character(String::from(text)))
+                } else {
+                    Err(invalid_data("value parse: bad character response data"))
+                }
+            },
+            Some(_) => Err(invalid_data("value parse: unknown response data type")),
+        }
+    }
+
+    /// Numeric value as `f64`, integers are converted
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            KsValue::Integer(x) => Some(x as f64),
+            KsValue::Decimal(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// Numeric value as `i64`, decimals are accepted only when they are exact integers
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            KsValue::Integer(x) => Some(x),
+            KsValue::Decimal(x) => {
+                if x.fract() == 0.0 && x >= i64::MIN as f64 && x < i64::MAX as f64 {
+                    Some(x as i64)
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Boolean value, either numeric `0`/`1` or character `OFF`/`ON`
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.as_i64() {
+            Some(0) => Some(false),
+            Some(1) => Some(true),
+            Some(_) => None,
+            None => match *self {
+                KsValue::Character(ref text) => {
+                    if text.eq_ignore_ascii_case("ON") {
+                        Some(true)
+                    } else if text.eq_ignore_ascii_case("OFF") {
+                        Some(false)
+                    } else {
+                        None
+                    }
+                },
+                _ => None,
+            },
+        }
+    }
+
+    /// Text of string or character response data
+    pub fn as_str_unquoted(&self) -> Option<&str> {
+        match *self {
+            KsValue::Character(ref text) | KsValue::String(ref text) => Some(text.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Content of block response data
+    pub fn as_block(&self) -> Option<&[u8]> {
+        match *self {
+            KsValue::Block(ref data) => Some(data.as_slice()),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for KsValue {
+    type Err = KsError;
+    fn from_str(text: &str) -> KsResult<Self> {
+        Self::parse(text)
+    }
+}
+
+/// IEEE 488.2 response message made of `;`-separated units of `,`-separated data elements
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct KsMessage {
+    units: Vec<Vec<KsValue>>,
+}
+
+impl KsMessage {
+    /// Parse whole response message, quoted strings and blocks may contain separators
+    ///
+    /// Indefinite block `#0` has no length, so as IEEE 488.2 requires it must be the last element:
+    /// it takes the rest of message including any `;` or `,` bytes, which are block content.
+    pub fn parse(bytes: &[u8]) -> KsResult<Self> {
+        let mut units = Vec::new();
+        let mut unit = Vec::new();
+        let mut pos = skip_whitespace(bytes, 0);
+        if pos == bytes.len() {
+            return Ok(Self { units });
+        }
+        loop {
+            unit.push(parse_element(bytes, &mut pos)?);
+            pos = skip_whitespace(bytes, pos);
+            match bytes.get(pos) {
+                None => break,
+                Some(b',') => (),
+                Some(b';') => units.push(unit.split_off(0)),
+                Some(_) => return Err(invalid_data("message parse: garbage after data element")),
+            }
+            pos = skip_whitespace(bytes, pos + 1);
+            if pos >= bytes.len() {
+                return Err(invalid_data("message parse: missing data element after separator"));
+            }
+        }
+        units.push(unit);
+        Ok(Self { units })
+    }
+
+    /// Response message units in order of queries
+    pub fn units(&self) -> &[Vec<KsValue>] {
+        &self.units
+    }
+
+    /// Number of response message units
+    pub fn len(&self) -> usize {
+        self.units.len()
+    }
+
+    /// Whether message contains no units
+    pub fn is_empty(&self) -> bool {
+        self.units.is_empty()
+    }
+
+    /// Iterate over data elements of all units
+    pub fn values(&self) -> impl Iterator<Item=&KsValue> {
+        self.units.iter().flatten()
+    }
+
+    /// Data elements of all units joined together
+    pub fn into_values(self) -> Vec<KsValue> {
+        self.units.into_iter().flatten().collect()
+    }
+}
+
+impl From<KsValue> for KsMessage {
+    fn from(value: KsValue) -> Self {
+        Self { units: vec![vec![value]] }
+    }
+}
+
+impl Index<usize> for KsMessage {
+    type Output = [KsValue];
+    fn index(&self, i: usize) -> &[KsValue] {
+        &self.units[i]
+    }
+}
+
+fn skip_whitespace(bytes: &[u8], mut pos: usize) -> usize {
+    while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+        pos += 1;
+    }
+    pos
+}
+
+fn parse_element(bytes: &[u8], pos: &mut usize) -> KsResult<KsValue> {
+    let start = *pos;
+    if bytes[start] == b'#' && bytes.get(start + 1).is_some_and(u8::is_ascii_digit) {
+        return parse_block(bytes, pos).map(KsValue::Block);
+    }
+    let mut quote = None;
+    while let Some(&c) = bytes.get(*pos) {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None => match c {
+                b'"' | b'\'' => quote = Some(c),
+                b',' | b';' => break,
+                _ => (),
+            },
+        }
+        *pos += 1;
+    }
+    str::from_utf8(&bytes[start..*pos])
+    .map_err(|_| invalid_data("message parse: non-utf8 sequence found"))
+    .and_then(KsValue::parse)
+}
+
+fn parse_block(bytes: &[u8], pos: &mut usize) -> KsResult<Vec<u8>> {
+    let n = (bytes[*pos + 1] - b'0') as usize;
+    *pos += 2;
+    if n == 0 {
+        // Indefinite block lasts until the end of message
+        let data = bytes[*pos..].to_vec();
+        *pos = bytes.len();
+        return Ok(data);
+    }
+    let size = bytes.get(*pos..(*pos + n))
+    .and_then(|digits| str::from_utf8(digits).ok())
+    .and_then(|digits| digits.parse::<usize>().ok())
+    .ok_or_else(|| invalid_data("message parse: error parse block size"))?;
+    *pos += n;
+    let data = pos.checked_add(size).and_then(|end| bytes.get(*pos..end))
+    .ok_or_else(|| invalid_data("message parse: block is truncated"))?
+    .to_vec();
+    *pos += size;
+    Ok(data)
+}
+
+fn parse_string(text: &str, quote: char) -> KsResult<String> {
+    let inner = text[1..].strip_suffix(quote)
+    .ok_or_else(|| invalid_data("value parse: unterminated string"))?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == quote && chars.next() != Some(quote) {
+            return Err(invalid_data("value parse: unescaped quote inside string"));
+        }
+        out.push(c);
+    }
+    Ok(out)
+}
+
+fn parse_numeric(text: &str) -> KsResult<KsValue> {
+    if !text.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c)) {
+        return Err(invalid_data("value parse: bad numeric response data"));
+    }
+    let digits = text.strip_prefix(|c| c == '+' || c == '-').unwrap_or(text);
+    if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        if let Ok(x) = text.parse::<i64>() {
+            return Ok(KsValue::Integer(x));
+        }
+    }
+    text.parse::<f64>()
+    .map(KsValue::Decimal)
+    .map_err(|_| invalid_data("value parse: bad numeric response data"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numeric() {
+        assert_eq!(KsValue::parse("+123").unwrap(), KsValue::Integer(123));
+        assert_eq!(KsValue::parse("-0.5").unwrap(), KsValue::Decimal(-0.5));
+        let x = KsValue::parse("+1.23456789E+00\r").unwrap();
+        assert_eq!(x, KsValue::Decimal(1.23456789));
+        assert_eq!(KsValue::parse("+1.00000000E+00").unwrap().as_i64(), Some(1));
+        assert!(KsValue::parse("+inf").is_err());
+    }
+
+    #[test]
+    fn boolean() {
+        assert_eq!(KsValue::parse("1").unwrap().as_bool(), Some(true));
+        assert_eq!(KsValue::parse("OFF").unwrap().as_bool(), Some(false));
+        assert_eq!(KsValue::parse("2").unwrap().as_bool(), None);
+    }
+
+    #[test]
+    fn character_and_string() {
+        let v = KsValue::parse("VOLT").unwrap();
+        assert_eq!(v, KsValue::Character(String::from("VOLT")));
+        assert_eq!(v.as_str_unquoted(), Some("VOLT"));
+
+        let v = KsValue::parse("\"My \"\"quoted\"\" string\"").unwrap();
+        assert_eq!(v.as_str_unquoted(), Some("My \"quoted\" string"));
+        assert_eq!(v.as_f64(), None);
+
+        assert!(KsValue::parse("\"unterminated").is_err());
+        assert!(KsValue::parse("\"bad \" quote\"").is_err());
+    }
+
+    #[test]
+    fn compound_message() {
+        let m = KsMessage::parse(b"\"Keysight, Inc\",1;+1.5E+00,-2,\"a;b\"").unwrap();
+        assert_eq!(m.len(), 2);
+        assert_eq!(m[0], [KsValue::String(String::from("Keysight, Inc")), KsValue::Integer(1)]);
+        assert_eq!(m[1][0], KsValue::Decimal(1.5));
+        assert_eq!(m[1][2].as_str_unquoted(), Some("a;b"));
+        assert_eq!(m.values().count(), 5);
+
+        assert!(KsMessage::parse(b"").unwrap().is_empty());
+        assert!(KsMessage::parse(b"1,,2").is_err());
+        for bytes in &[&b"1,"[..], b"1;", b"1, "] {
+            match KsMessage::parse(bytes) {
+                Err(KsError::BadResponse(_)) => (),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn message_with_blocks() {
+        let m = KsMessage::parse(b"1,#13a,;;#0\x00;").unwrap();
+        assert_eq!(m[0], [KsValue::Integer(1), KsValue::Block(b"a,;".to_vec())]);
+        assert_eq!(m[0][1].as_block(), Some(&b"a,;"[..]));
+        assert_eq!(m.len(), 2);
+        assert_eq!(m[1], [KsValue::Block(vec![0, b';'])]);
+
+        // Indefinite block is never followed by other elements
+        let m = KsMessage::parse(b"1;#0ab;2,3").unwrap();
+        assert_eq!(m.len(), 2);
+        assert_eq!(m[1], [KsValue::Block(b"ab;2,3".to_vec())]);
+
+        assert!(KsMessage::parse(b"#15ab").is_err());
+        assert!(KsMessage::parse(b"#12ab3").is_err());
+    }
+}